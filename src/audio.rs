@@ -1,81 +1,145 @@
-mod song;
+mod cart;
+mod crossfader;
+mod deck;
+mod ducker;
+mod flac;
 mod jack_util;
+mod limiter;
+mod loudness;
+mod markers;
+mod meter;
+mod mixer;
+pub mod offline;
+mod overview;
+mod queue;
+mod recorder;
+mod resample;
+mod smoothing;
+mod song;
+mod stretch;
+mod tags;
+mod true_peak;
 
-use song::Song;
+use crate::config::{CartConfig, Config, DuckerConfig, LimiterConfig, NormalizeConfig};
+pub use crossfader::CrossfadeCurve;
 use jack_util::{JackBoxProcHandler, JackNotifs};
+pub use markers::{Marker, Markers};
+pub use mixer::Mixer;
+pub use overview::Overview;
+pub use queue::EndMode;
+use recorder::Recorder;
+pub use recorder::RecordingStatus;
+use song::Song;
+pub use song::SongLevels;
 
 use imgui::{ImStr, ImString};
 use jack::{AsyncClient, AudioIn, AudioOut, Client, ClientOptions, Control, ProcessScope};
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering::Relaxed};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+
+/// Fader level in dB at and below which a fader is fully off
+pub const MIN_DB: f32 = -60.;
+
+/// Number of playback decks
+pub const DECKS: usize = 2;
+
+/// Index of the cue player, which has the same transport as the decks and comes after them. It
+/// plays on the cue bus only, so songs can be previewed off air.
+pub const CUE: usize = DECKS;
 
 /// Audio system. Connection to jack and state related to playing music.
 pub struct Audio {
     /// Available songs and index in the songs vec (owned by Jack thread)
     song_index_map: BTreeMap<ImString, usize>,
 
+    /// Name of each song, by index in the songs vec
+    song_names: Vec<ImString>,
+
+    /// Levels of each song, by index in the songs vec
+    song_levels: Vec<SongLevels>,
+
+    /// Markers of each song, by index in the songs vec. The mixer has its own copy, which is
+    /// sent every change.
+    song_markers: RefCell<Vec<Markers>>,
+
+    /// Waveform overview of each song, by index in the songs vec. Filled in by a background
+    /// thread after loading.
+    overviews: Arc<Vec<OnceLock<Overview>>>,
+
+    /// Names of the input channel strips, in ui order
+    input_names: Vec<ImString>,
+
     /// Jack context
     _jack_client: AsyncClient<JackNotifs, JackBoxProcHandler>,
 
     /// Channel with audio thread
     ac_send: Sender<AudioControl>,
 
+    /// Play queues the audio thread replaced, to be freed here
+    old_queues: Receiver<VecDeque<usize>>,
+
+    /// Recorder of the output and sources
+    recorder: Recorder,
+
     /// Atomics related to current state of music being played
     music: SharedAtomics,
 }
 
 impl Audio {
-    /// Initialize and return audio system.
-    pub fn init() -> Audio {
+    /// Initialize and return audio system, with the input strips and connections from config.
+    pub fn init(config: &Config) -> Audio {
         // Setup jack
         let jack = Client::new("JamesMixer", ClientOptions::NO_START_SERVER)
             .expect("Jackd is not running")
             .0;
 
-        // Load songs
-        let (song_index_map, songs) = load_songs();
+        // Load songs, resampled to the rate jack is running at
+        let sample_rate = jack.sample_rate() as u32;
+        let (song_index_map, songs) = load_songs(sample_rate, &config.normalize);
+        let song_levels = songs.iter().map(|song| song.levels).collect();
+        let song_markers = RefCell::new(songs.iter().map(|song| song.markers).collect());
+        let overviews = overviews(&songs);
+        let carts = load_carts(sample_rate, &config.carts);
 
-        // Create jack ports
-        let mic_in = jack.register_port("mic_in", AudioIn::default()).unwrap();
-        let line_in = jack.register_port("line_in", AudioIn::default()).unwrap();
-        let mut output = jack.register_port("output", AudioOut::default()).unwrap();
+        // Create jack ports, one input port per channel strip
+        let inputs: Vec<_> = config
+            .inputs
+            .iter()
+            .map(|input| jack.register_port(&input.port, AudioIn).unwrap())
+            .collect();
+        let mut output_left = jack.register_port("output_left", AudioOut).unwrap();
+        let mut output_right = jack.register_port("output_right", AudioOut).unwrap();
+        let mut cue_out = jack.register_port("cue_out", AudioOut).unwrap();
 
-        // Init MusicThread struct for closure
-        let (ac_send, ac_recv) = channel();
-        let shared = SharedAtomics {
-            timestamp: Arc::new(AtomicUsize::new(0)),
-            max_timestamp: Arc::new(AtomicUsize::new(0)),
-            paused: Arc::new(AtomicBool::new(true)),
-            mic_volume: Arc::new(AtomicU32::new(0)),
-            line_volume: Arc::new(AtomicU32::new(0)),
-            song_volume: Arc::new(AtomicU32::new(0)),
-        };
+        // Init mixing engine for closure
+        let (mut mixer, ac_send, shared, old_queues) =
+            Mixer::new(songs, carts, sample_rate, config);
 
-        let mut music = MusicThread {
-            ac_recv,
-            songs,
-            shared: shared.clone(),
-            song: None,
-        };
+        // Recorder, fed by the mixer
+        let (recorder, record_tap) = Recorder::new(sample_rate, config);
+        mixer.set_record_tap(record_tap);
 
         // Callback closure that will be called by jack to update sound data buffer
         let process_callback = move |_: &Client, ps: &ProcessScope| -> Control {
-            audio_callback(
-                output.as_mut_slice(ps),
-                mic_in.as_slice(ps),
-                line_in.as_slice(ps),
-                &mut music,
+            mixer.process(
+                output_left.as_mut_slice(ps),
+                output_right.as_mut_slice(ps),
+                cue_out.as_mut_slice(ps),
+                inputs.iter().map(|input| input.as_slice(ps)),
             );
             Control::Continue
         };
         let process = JackBoxProcHandler(Box::new(process_callback));
 
         // Attach callbacks to jack
-        let async_client = jack.activate_async(JackNotifs::default(), process).unwrap();
+        let notifs = JackNotifs::new(sample_rate, shared.jack_rate.clone());
+        let async_client = jack.activate_async(notifs, process).unwrap();
 
         // Connect jack ports. This requires a second client, due I suppose to a limitation in
         // the jack library.
@@ -83,31 +147,61 @@ impl Audio {
             .unwrap()
             .0;
 
-        // Macro to beautify jack port connection
-        macro_rules! ports { ($($p1:expr => $p2:expr)*) => {
-            $(match jtemp.connect_ports_by_name($p1, $p2) {
-                Ok(()) => println!("{:18} => {:18} connected", $p1, $p2),
-                Err(_) => println!("{:18} => {:18} NOT CONNECTED", $p1, $p2),
-            };)*
-        }; }
-
-        // My setup for the show. In the future this could be read from a file, or deferred
-        // to an external script or the user.
-        // jack_capture automatically connects when the port is found, so it is not needed here.
-        ports! {
-            "system:capture_1" => "JamesMixer:mic_in"
-            "system:capture_2" => "JamesMixer:mic_in"
-            "line_in:capture_1" => "JamesMixer:line_in"
-            "line_in:capture_2" => "JamesMixer:line_in"
-            "JamesMixer:output" => "system:playback_1"
-            "JamesMixer:output" => "system:playback_2"
-            "JamesMixer:output" => "darkice:mono"
+        // Closure to beautify jack port connection. A port jack does not have is named, as it
+        // is most likely a typo in the config or an old config's port.
+        let connect = |p1: &str, p2: &str| {
+            if let Some(missing) = [p1, p2].iter().find(|p| jtemp.port_by_name(p).is_none()) {
+                println!("{:18} => {:18} NOT CONNECTED: no port {}", p1, p2, missing);
+                return;
+            }
+
+            match jtemp.connect_ports_by_name(p1, p2) {
+                Ok(()) => println!("{:18} => {:18} connected", p1, p2),
+                Err(_) => println!("{:18} => {:18} NOT CONNECTED", p1, p2),
+            }
+        };
+
+        // Connections for the show, as set in the config
+        for input in &config.inputs {
+            for source in &input.connect {
+                connect(source, &format!("JamesMixer:{}", input.port));
+            }
+        }
+
+        for dest in &config.output.left {
+            connect("JamesMixer:output_left", dest);
         }
 
+        for dest in &config.output.right {
+            connect("JamesMixer:output_right", dest);
+        }
+
+        for dest in &config.output.cue {
+            connect("JamesMixer:cue_out", dest);
+        }
+
+        let mut song_names = vec![ImString::default(); song_index_map.len()];
+        for (name, &i) in &song_index_map {
+            song_names[i] = name.clone();
+        }
+
+        let input_names = config
+            .inputs
+            .iter()
+            .map(|input| ImString::new(&input.name))
+            .collect();
+
         Audio {
             song_index_map,
+            song_names,
+            song_levels,
+            song_markers,
+            overviews,
+            recorder,
+            input_names,
             _jack_client: async_client,
             ac_send,
+            old_queues,
             music: shared,
         }
     }
@@ -117,192 +211,877 @@ impl Audio {
         self.song_index_map.keys().map(|s| s.as_ref()).collect()
     }
 
-    /// Returns true if music is paused. This may not necessarily follow what was set using
-    /// play_music and pause_music.
-    pub fn get_paused(&self) -> bool {
-        self.music.paused.load(Relaxed)
+    /// Returns the measured levels of a song, and the gain it plays with
+    pub fn song_levels(&self, name: &ImStr) -> SongLevels {
+        self.song_levels[self.song_index_map[name]]
+    }
+
+    /// Returns the name of the song on a deck, or None if no song was loaded yet. Follows the
+    /// deck as it goes on to the next song.
+    pub fn deck_song(&self, deck: usize) -> Option<&ImStr> {
+        let i = self.music.decks[deck].song.load(Relaxed);
+        self.song_names.get(i).map(|name| name.as_ref())
     }
 
-    /// Returns the current timestamp and maximum timestamp of the current song in format (mm, ss).
+    /// Returns the waveform overview of the song on a deck, or None if no song was loaded or its
+    /// overview is not ready yet
+    pub fn deck_overview(&self, deck: usize) -> Option<&Overview> {
+        let i = self.music.decks[deck].song.load(Relaxed);
+        self.overviews.get(i)?.get()
+    }
+
+    /// Returns the markers of the song on a deck, or no markers if no song was loaded
+    pub fn deck_markers(&self, deck: usize) -> Markers {
+        let i = self.music.decks[deck].song.load(Relaxed);
+        self.song_markers
+            .borrow()
+            .get(i)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets a marker of the song on a deck, or clears it with None, and saves the song's
+    /// markers next to it in ./music. A marker that would be out of order is not set, and no
+    /// marker is while the songs are stopped for a change of jack's rate.
+    pub fn set_marker(&self, deck: usize, marker: Marker, frame: Option<usize>) {
+        if self.rate_mismatch().is_some() {
+            return;
+        }
+
+        let i = self.music.decks[deck].song.load(Relaxed);
+        let mut song_markers = self.song_markers.borrow_mut();
+        let markers = match song_markers.get_mut(i) {
+            Some(markers) => markers,
+            None => return,
+        };
+
+        if !markers.set(marker, frame) {
+            return;
+        }
+        let path = PathBuf::from("./music").join(self.song_names[i].to_str());
+        markers.save(&path, self.music.sample_rate.load(Relaxed) as u32);
+        self.ac_send
+            .send(AudioControl::Markers(i, *markers))
+            .unwrap();
+    }
+
+    /// Returns the whole seconds left until the intro of the song on a deck ends, rounded up, or
+    /// None if the song has no intro marker or is past it
+    pub fn intro_countdown(&self, deck: usize) -> Option<usize> {
+        let intro_end = self.deck_markers(deck).get(Marker::IntroEnd)?;
+        let (timestamp, _) = self.music_samples(deck);
+        let sample_rate = self.music.sample_rate.load(Relaxed);
+        let left = intro_end.checked_sub(timestamp).filter(|&left| left > 0)?;
+        Some(left.div_ceil(sample_rate))
+    }
+
+    /// Returns the sample rate the songs play at
+    pub fn sample_rate(&self) -> usize {
+        self.music.sample_rate.load(Relaxed)
+    }
+
+    /// Returns the rate jack changed to if it no longer runs at the songs' rate, in which case
+    /// the songs and carts are stopped
+    pub fn rate_mismatch(&self) -> Option<usize> {
+        let jack_rate = self.music.jack_rate.load(Relaxed);
+        (jack_rate != self.sample_rate()).then_some(jack_rate)
+    }
+
+    /// Returns true if a deck is paused. This may not necessarily follow what was set using
+    /// set_paused.
+    pub fn get_paused(&self, deck: usize) -> bool {
+        self.music.decks[deck].paused.load(Relaxed)
+    }
+
+    /// Returns the current timestamp and maximum timestamp of a deck's song in format (mm, ss).
     /// Returns 00:00 for both values if no song is loaded yet.
-    pub fn music_timestamp(&self) -> ((usize, usize), (usize, usize)) {
+    pub fn music_timestamp(&self, deck: usize) -> ((usize, usize), (usize, usize)) {
+        let sample_rate = self.music.sample_rate.load(Relaxed);
+        let (timestamp, max_timestamp) = self.music_samples(deck);
         (
-            samples_to_minsec(self.music.timestamp.load(Relaxed)),
-            samples_to_minsec(self.music.max_timestamp.load(Relaxed)),
+            samples_to_minsec(timestamp, sample_rate),
+            samples_to_minsec(max_timestamp, sample_rate),
         )
     }
 
-    /// Returns the current timestamp and maximum timestamp in samples. Returns 0 for both values
-    /// if no song is loaded yet.
-    pub fn music_samples(&self) -> (usize, usize) {
+    /// Returns the current timestamp and maximum timestamp of a deck in samples. Returns 0 for
+    /// both values if no song is loaded yet.
+    pub fn music_samples(&self, deck: usize) -> (usize, usize) {
+        let atomics = &self.music.decks[deck];
         (
-            self.music.timestamp.load(Relaxed),
-            self.music.max_timestamp.load(Relaxed),
+            atomics.timestamp.load(Relaxed),
+            atomics.max_timestamp.load(Relaxed),
         )
     }
 
-    /// Pauses or attempts to unpause music. "Attempts" because if no song has been loaded yet,
+    /// Pauses or attempts to unpause a deck. "Attempts" because if no song has been loaded yet,
     /// the paused state will not change, as it doesnt make sense to play an unselected song.
-    pub fn set_paused(&self, value: bool) {
-        self.ac_send.send(AudioControl::Paused(value)).unwrap();
+    pub fn set_paused(&self, deck: usize, value: bool) {
+        self.deck_control(deck, DeckControl::Paused(value));
     }
 
-    /// Readies playing this song. Also pauses music and jumps to timestamp 00:00, and updates
-    /// max_timestamp.
-    pub fn load_song(&self, name: &ImStr) {
+    /// Fades a deck out over the given number of seconds, then pauses it. Playing again before
+    /// the fade is over cancels it.
+    pub fn fade_out(&self, deck: usize, seconds: f32) {
+        let samples = self.music.sample_rate.load(Relaxed) as f32 * seconds;
+        self.deck_control(deck, DeckControl::FadeOut(samples as usize));
+    }
+
+    /// Returns true while a deck is fading out to pause
+    pub fn get_fading(&self, deck: usize) -> bool {
+        self.music.decks[deck].fading.load(Relaxed)
+    }
+
+    /// Readies playing this song on a deck. Also pauses the deck and jumps to timestamp 00:00,
+    /// and updates max_timestamp.
+    pub fn load_song(&self, deck: usize, name: &ImStr) {
         let i = self.song_index_map[name];
-        self.ac_send.send(AudioControl::Load(i)).unwrap();
+        self.deck_control(deck, DeckControl::Load(i));
+    }
+
+    /// Takes in a timestamp in seconds to jump to in a deck's song. If the timestamp is out of
+    /// range, or no song was loaded yet, it does nothing.
+    pub fn jump_song(&self, deck: usize, seconds: f64) {
+        let samples = self.music.sample_rate.load(Relaxed) as f64 * seconds;
+        self.jump_samples(deck, samples.round() as usize);
+    }
+
+    /// Jumps to a timestamp in samples in a deck's song. A jump that is still waiting for the
+    /// song to fade out is replaced, so dragging through the song only seeks to where it ends up.
+    pub fn jump_samples(&self, deck: usize, samples: usize) {
+        self.deck_control(deck, DeckControl::JumpTo(samples));
+    }
+
+    /// Jumps forward or, with negative seconds, back in a deck's song, no further than its start
+    /// or end
+    pub fn skip(&self, deck: usize, seconds: f64) {
+        let (timestamp, max_timestamp) = self.music_samples(deck);
+        let offset = self.music.sample_rate.load(Relaxed) as f64 * seconds;
+        let samples = (timestamp as f64 + offset)
+            .round()
+            .clamp(0., max_timestamp as f64);
+        self.jump_samples(deck, samples as usize);
+    }
+
+    /// Sets the loop start of a deck to a timestamp in samples
+    pub fn set_loop_start(&self, deck: usize, samples: usize) {
+        self.deck_control(deck, DeckControl::LoopStart(samples));
+    }
+
+    /// Sets the loop end of a deck to a timestamp in samples, and starts looping. Does nothing
+    /// unless the loop start is before it.
+    pub fn set_loop_end(&self, deck: usize, samples: usize) {
+        self.deck_control(deck, DeckControl::LoopEnd(samples));
+    }
+
+    /// Turns looping of a deck on or off. Turning it off carries on past the loop end right away.
+    pub fn set_looping(&self, deck: usize, value: bool) {
+        self.deck_control(deck, DeckControl::Looping(value));
+    }
+
+    /// Makes a deck leave its loop the next time it reaches the loop end
+    pub fn exit_loop(&self, deck: usize) {
+        self.deck_control(deck, DeckControl::ExitLoop);
+    }
+
+    /// Returns the loop start and end of a deck in samples, None where not set
+    pub fn loop_points(&self, deck: usize) -> (Option<usize>, Option<usize>) {
+        let atomics = &self.music.decks[deck];
+        let point = |n: usize| Some(n).filter(|&n| n != usize::MAX);
+        (
+            point(atomics.loop_start.load(Relaxed)),
+            point(atomics.loop_end.load(Relaxed)),
+        )
+    }
+
+    /// Returns true while a deck loops
+    pub fn get_looping(&self, deck: usize) -> bool {
+        self.music.decks[deck].looping.load(Relaxed)
+    }
+
+    /// Returns true while a deck is set to leave its loop at the loop end
+    pub fn get_exiting_loop(&self, deck: usize) -> bool {
+        self.music.decks[deck].loop_exit.load(Relaxed)
+    }
+
+    /// Sets the tempo of a deck in percent off the song's own, keeping its pitch
+    pub fn set_tempo(&self, deck: usize, percent: f32) {
+        let tempo = 1. + percent / 100.;
+        self.music.decks[deck].tempo.store(tempo.to_bits(), Relaxed);
+    }
+
+    /// Shifts the pitch of a deck by a number of semitones, keeping its tempo
+    pub fn set_pitch(&self, deck: usize, semitones: f32) {
+        self.music.decks[deck]
+            .pitch
+            .store(semitones.to_bits(), Relaxed);
+    }
+
+    fn deck_control(&self, deck: usize, control: DeckControl) {
+        self.ac_send
+            .send(AudioControl::Deck(deck, control))
+            .unwrap();
+    }
+
+    /// Replaces the play queue with the named songs, next first. seen is the number of songs
+    /// the engine had taken off the queue, as last read with queue_advanced.
+    pub fn set_queue(&self, names: &[ImString], seen: usize) {
+        self.old_queues.try_iter().for_each(drop);
+        let songs = names.iter().map(|name| self.song_index_map[name]).collect();
+        self.ac_send.send(AudioControl::Queue(songs, seen)).unwrap();
+    }
+
+    /// Returns the number of songs the decks have taken off the play queue so far
+    pub fn queue_advanced(&self) -> usize {
+        self.music.queue.advanced.load(Relaxed)
+    }
+
+    /// Sets what the decks do when their song ends
+    pub fn set_end_mode(&self, mode: EndMode) {
+        self.music.queue.set_end_mode(mode);
+    }
+
+    /// Sets how many seconds before a song ends the next one starts, crossfading the two. 0
+    /// plays them back to back.
+    pub fn set_overlap(&self, seconds: f32) {
+        let samples = self.music.sample_rate.load(Relaxed) as f32 * seconds;
+        self.music.queue.overlap.store(samples as usize, Relaxed);
+    }
+
+    /// Moves the crossfader, from 0 for deck A only to 1 for deck B only
+    pub fn set_crossfader(&self, position: f32) {
+        let crossfader = &self.music.crossfader;
+        crossfader.position.store(position.to_bits(), Relaxed);
+    }
+
+    /// Sets the curve the crossfader splits the level between the decks with
+    pub fn set_crossfade_curve(&self, curve: CrossfadeCurve) {
+        self.music.crossfader.set_curve(curve);
+    }
+
+    /// Returns the names of the input channel strips, in the order they are configured.
+    pub fn input_names(&self) -> &[ImString] {
+        &self.input_names
+    }
+
+    /// Sets the volume of a channel strip, in dB. 0 dB is unity gain.
+    pub fn set_volume(&self, strip: Strip, db: f32) {
+        let v = db_to_gain(db);
+        self.music.strip(strip).volume.store(v.to_bits(), Relaxed);
+    }
+
+    /// Sets the master output volume, in dB. 0 dB is unity gain.
+    pub fn set_master_volume(&self, db: f32) {
+        let v = db_to_gain(db);
+        self.music.master_volume.store(v.to_bits(), Relaxed);
+    }
+
+    /// Sets the volume of the cue bus, in dB. 0 dB is unity gain.
+    pub fn set_cue_volume(&self, db: f32) {
+        let v = db_to_gain(db);
+        self.music.cue_volume.store(v.to_bits(), Relaxed);
+    }
+
+    /// Sets the limiter ceiling, in dBTP
+    pub fn set_limiter_ceiling(&self, db: f32) {
+        self.music.limiter.ceiling.store(db.to_bits(), Relaxed);
+    }
+
+    /// Sets the limiter release time, in ms
+    pub fn set_limiter_release(&self, ms: f32) {
+        self.music.limiter.release.store(ms.to_bits(), Relaxed);
+    }
+
+    /// Bypasses or enables the limiter
+    pub fn set_limiter_bypass(&self, value: bool) {
+        self.music.limiter.bypass.store(value, Relaxed);
+    }
+
+    /// Returns the gain reduction the limiter applied during the last block, in dB
+    pub fn limiter_reduction(&self) -> f32 {
+        f32::from_bits(self.music.limiter.reduction.load(Relaxed))
+    }
+
+    /// Turns ducking of the music on or off
+    pub fn set_ducker_enabled(&self, value: bool) {
+        self.music.ducker.enabled.store(value, Relaxed);
+    }
+
+    /// Sets the level in dBFS a keying input has to go over to duck the music
+    pub fn set_ducker_threshold(&self, db: f32) {
+        self.music.ducker.threshold.store(db.to_bits(), Relaxed);
+    }
+
+    /// Sets how far the music is ducked, in dB
+    pub fn set_ducker_depth(&self, db: f32) {
+        self.music.ducker.depth.store(db.to_bits(), Relaxed);
     }
 
-    /// Takes in a timestamp in minutes and seconds to jump to in the song, and pauses music.
-    /// If the timestamp is out of range, or no song was loaded yet, it does nothing.
-    pub fn jump_song(&self, minutes: usize, seconds: usize) {
-        let samples = 48000 * (minutes * 60 + seconds);
-        self.ac_send.send(AudioControl::JumpTo(samples)).unwrap();
+    /// Sets the ducker's attack, hold and release times, in ms
+    pub fn set_ducker_times(&self, attack: f32, hold: f32, release: f32) {
+        let ducker = &self.music.ducker;
+        ducker.attack.store(attack.to_bits(), Relaxed);
+        ducker.hold.store(hold.to_bits(), Relaxed);
+        ducker.release.store(release.to_bits(), Relaxed);
     }
 
-    /// Sets microphone volume
-    pub fn set_mic_volume(&self, value: f32) {
-        let v = value / 100.;
-        self.music.mic_volume.store(v.to_bits(), Relaxed);
+    /// Returns the largest gain reduction the ducker applied to the music during the last
+    /// block, in dB
+    pub fn ducker_reduction(&self) -> f32 {
+        f32::from_bits(self.music.ducker.reduction.load(Relaxed))
     }
 
-    /// Sets line in volume
-    pub fn set_line_volume(&self, value: f32) {
-        let v = value / 100.;
-        self.music.line_volume.store(v.to_bits(), Relaxed);
+    /// Makes an input strip key the ducker or not
+    pub fn set_duck(&self, input: usize, value: bool) {
+        self.music.inputs[input].duck.store(value, Relaxed);
     }
 
-    /// Sets song volume
-    pub fn set_song_volume(&self, value: f32) {
-        let v = value / 100.;
-        self.music.song_volume.store(v.to_bits(), Relaxed);
+    /// Mutes or unmutes a channel strip. The volume is kept while muted.
+    pub fn set_mute(&self, strip: Strip, value: bool) {
+        self.music.strip(strip).mute.store(value, Relaxed);
     }
+
+    /// Solos or unsolos a channel strip. While any strip is soloed, only soloed strips are
+    /// heard.
+    pub fn set_solo(&self, strip: Strip, value: bool) {
+        self.music.strip(strip).solo.store(value, Relaxed);
+    }
+
+    /// Inverts the polarity of a channel strip
+    pub fn set_invert(&self, strip: Strip, value: bool) {
+        self.music.strip(strip).invert.store(value, Relaxed);
+    }
+
+    /// Returns the pre-fader peak and RMS level of a channel strip, as linear amplitudes. The
+    /// peak is the highest since the last call.
+    pub fn strip_meter(&self, strip: Strip) -> (f32, f32) {
+        self.music.strip(strip).meter.read()
+    }
+
+    /// Returns the peak and RMS level of the left and right outputs after the limiter, as
+    /// linear amplitudes. The peaks are the highest since the last call.
+    pub fn output_meters(&self) -> [(f32, f32); 2] {
+        let [left, right] = &*self.music.output_meters;
+        [left.read(), right.read()]
+    }
+
+    /// Starts recording to new files. Returns the path of the output's file.
+    pub fn start_recording(&self) -> PathBuf {
+        self.recorder.start()
+    }
+
+    /// Stops recording
+    pub fn stop_recording(&self) {
+        self.recorder.stop();
+    }
+
+    /// Returns the elapsed time and file size of the recording, or None if not recording
+    pub fn recording_status(&self) -> Option<RecordingStatus> {
+        self.recorder
+            .status(self.music.sample_rate.load(Relaxed) as u32)
+    }
+
+    /// Returns why recordings are not made as configured, or None if they are
+    pub fn recording_notice(&self) -> Option<&str> {
+        self.recorder.notice()
+    }
+
+    /// Returns the loudness of the output
+    pub fn loudness(&self) -> Loudness {
+        let loudness = &self.music.loudness;
+        Loudness {
+            momentary: f32::from_bits(loudness.momentary.load(Relaxed)),
+            short_term: f32::from_bits(loudness.short_term.load(Relaxed)),
+            integrated: f32::from_bits(loudness.integrated.load(Relaxed)),
+            range: f32::from_bits(loudness.range.load(Relaxed)),
+        }
+    }
+
+    /// Restarts measuring the integrated loudness and loudness range
+    pub fn reset_loudness(&self) {
+        self.music.loudness.reset.store(true, Relaxed);
+    }
+
+    /// Triggers a cart of the cart wall
+    pub fn trigger_cart(&self, cart: usize) {
+        self.ac_send.send(AudioControl::Cart(cart)).unwrap();
+    }
+
+    /// Fades out every playing cart
+    pub fn stop_carts(&self) {
+        self.ac_send.send(AudioControl::StopCarts).unwrap();
+    }
+
+    /// Sets the gain of a cart, in dB
+    pub fn set_cart_gain(&self, cart: usize, db: f32) {
+        let v = db_to_gain(db);
+        self.music.carts[cart].gain.store(v.to_bits(), Relaxed);
+    }
+
+    /// Returns the seconds left to play of a cart, 0 if it is not playing
+    pub fn cart_remaining(&self, cart: usize) -> f32 {
+        let frames = self.music.carts[cart].remaining.load(Relaxed);
+        frames as f32 / self.music.sample_rate.load(Relaxed) as f32
+    }
+}
+
+/// Loudness readings of the output, following EBU R128. Loudness is in LUFS, and -inf until
+/// there is anything to measure.
+pub struct Loudness {
+    pub momentary: f32,
+    pub short_term: f32,
+    pub integrated: f32,
+
+    // Loudness range in LU
+    pub range: f32,
+}
+
+/// Identifies a channel strip of the mixer
+#[derive(Clone, Copy)]
+pub enum Strip {
+    /// Input strip, by its index in the config
+    Input(usize),
+
+    /// The music player
+    Music,
 }
 
 /// Struct that contains the atomics that are shared between ui and audio thread
 #[derive(Clone)]
-struct SharedAtomics {
+pub struct SharedAtomics {
+    // Transport of each playback deck, and of the cue player last
+    decks: Arc<[DeckAtomics; DECKS + 1]>,
+
+    // The coefficient volume (0-1+) of the cue bus
+    cue_volume: Arc<AtomicU32>,
+
+    // Crossfader between the decks
+    crossfader: Arc<CrossfaderAtomics>,
+
+    // Play queue settings and progress
+    queue: Arc<QueueAtomics>,
+
+    // Sample rate the engine, songs and carts run at, in Hz
+    sample_rate: Arc<AtomicUsize>,
+
+    // Sample rate jack runs at, in Hz. The songs and carts can only play at sample_rate, so they
+    // are stopped while it differs. The input strips stay live.
+    jack_rate: Arc<AtomicUsize>,
+
+    // Atomics of each input channel strip
+    inputs: Arc<Vec<StripAtomics>>,
+
+    // Atomics of the music player's channel strip
+    song_strip: Arc<StripAtomics>,
+
+    // The coefficient volume (0-1+) of the summed output
+    master_volume: Arc<AtomicU32>,
+
+    // Output limiter settings and readout
+    limiter: Arc<LimiterAtomics>,
+
+    // Music ducker settings and readout
+    ducker: Arc<DuckerAtomics>,
+
+    // Levels of the left and right outputs
+    output_meters: Arc<[MeterAtomics; 2]>,
+
+    // Loudness of the output
+    loudness: Arc<LoudnessAtomics>,
+
+    // Atomics of each cart of the cart wall
+    carts: Arc<Vec<CartAtomics>>,
+}
+
+/// Atomics for one channel strip
+pub struct StripAtomics {
+    // The coefficient volume (0-1+) of the strip
+    volume: AtomicU32,
+
+    // True if the strip is muted, which overrides solo
+    mute: AtomicBool,
+
+    // True if the strip is soloed
+    solo: AtomicBool,
+
+    // True if the strip's polarity is inverted
+    invert: AtomicBool,
+
+    // True if the strip keys the ducker. Only used for input strips.
+    duck: AtomicBool,
+
+    // Level of the strip's input, before the fader
+    meter: MeterAtomics,
+}
+
+/// Atomics for the transport of one playback deck
+pub struct DeckAtomics {
+    // Index of the loaded song, usize::MAX if none
+    song: AtomicUsize,
+
     // Timestamp in samples.
-    timestamp: Arc<AtomicUsize>,
+    timestamp: AtomicUsize,
 
     // Maximum possible timestamp in samples.
-    max_timestamp: Arc<AtomicUsize>,
+    max_timestamp: AtomicUsize,
+
+    // True if the deck is currently paused
+    paused: AtomicBool,
+
+    // True while the deck is fading out to pause
+    fading: AtomicBool,
 
-    // True if music is currently paused
-    paused: Arc<AtomicBool>,
+    // Loop points in samples, usize::MAX if not set
+    loop_start: AtomicUsize,
+    loop_end: AtomicUsize,
 
-    // The coefficient volume (0-1+) of the three inputs
-    mic_volume: Arc<AtomicU32>,
-    line_volume: Arc<AtomicU32>,
-    song_volume: Arc<AtomicU32>,
+    // True while the deck loops, and while it is set to leave the loop at its end
+    looping: AtomicBool,
+    loop_exit: AtomicBool,
+
+    // Tempo as a ratio of the song's own, and pitch shift in semitones, as f32 bits
+    tempo: AtomicU32,
+    pitch: AtomicU32,
 }
 
-enum AudioControl {
-    Paused(bool),
-    Load(usize),
-    JumpTo(usize),
+/// Atomics for the play queue
+pub struct QueueAtomics {
+    // Number of songs taken off the queue so far, set by the audio thread
+    advanced: AtomicUsize,
+
+    // Index of the end mode in EndMode::ALL
+    end_mode: AtomicUsize,
+
+    // Frames a song starts before the previous one ends
+    overlap: AtomicUsize,
 }
 
-struct MusicThread {
-    // Receiver for controls from the ui thread
-    ac_recv: Receiver<AudioControl>,
+/// Atomics for the crossfader
+pub struct CrossfaderAtomics {
+    // Position from 0 (deck A only) to 1 (deck B only)
+    position: AtomicU32,
 
-    // Song data that can be played given an index which is calculated in the ui thread
-    songs: Vec<Song>,
+    // Index of the curve in CrossfadeCurve::ALL
+    curve: AtomicUsize,
+}
 
-    // Atomics shared between audio and ui threads
-    shared: SharedAtomics,
+/// Atomics for one cart
+pub struct CartAtomics {
+    // The coefficient volume (0-1+) of the cart
+    gain: AtomicU32,
 
-    // Currently playing song
-    song: Option<usize>,
+    // Frames left to play of the cart's latest trigger, 0 when it is not playing. Set by the
+    // audio thread.
+    remaining: AtomicUsize,
 }
 
-fn audio_callback(out: &mut [f32], mic: &[f32], line: &[f32], music: &mut MusicThread) {
-    use AudioControl::*;
-
-    // A macro for easier working with atomics in the MusicThread struct
-    #[rustfmt::skip]
-    macro_rules! atom {
-        ($name:ident)             => { music.shared.$name.load(Relaxed)        };
-        ($name:ident = $val:expr) => { music.shared.$name.store($val, Relaxed) };
-    }
-
-    // Handle all music controls
-    for c in music.ac_recv.try_iter() {
-        match c {
-            // Select song, and reset song playing variables
-            Load(i) => {
-                music.song = Some(i);
-                atom!(timestamp = 0);
-                atom!(max_timestamp = music.songs[i].data.len());
-                atom!(paused = true);
-            }
+/// Atomics for the output loudness meter
+pub struct LoudnessAtomics {
+    // Momentary, short-term and integrated loudness in LUFS, set by the audio thread
+    momentary: AtomicU32,
+    short_term: AtomicU32,
+    integrated: AtomicU32,
 
-            // Set paused status. Pausing always works, but unpausing only works
-            // when a song has been loaded, and music.song holds a value.
-            Paused(p) => atom!(paused = p || music.song.is_none()),
+    // Loudness range in LU, set by the audio thread
+    range: AtomicU32,
 
-            // If requested time within range of song, timestamp = time
-            JumpTo(n) => {
-                if n <= atom!(max_timestamp) {
-                    atom!(timestamp = n);
-                }
-            }
+    // Set by the ui to restart the integrated loudness and loudness range measurement
+    reset: AtomicBool,
+}
+
+/// Atomics for a level meter, set by the audio thread
+pub struct MeterAtomics {
+    // Highest absolute sample since the ui last read it
+    peak: AtomicU32,
+
+    // RMS level
+    rms: AtomicU32,
+}
+
+/// Atomics for the music ducker
+pub struct DuckerAtomics {
+    // True if ducking is on
+    enabled: AtomicBool,
+
+    // Threshold in dBFS, depth in dB, and attack, hold and release times in ms, as f32 bits
+    threshold: AtomicU32,
+    depth: AtomicU32,
+    attack: AtomicU32,
+    hold: AtomicU32,
+    release: AtomicU32,
+
+    // Largest gain reduction during the last block in dB, set by the audio thread
+    reduction: AtomicU32,
+}
+
+/// Atomics for the output limiter
+pub struct LimiterAtomics {
+    // Ceiling in dBTP
+    ceiling: AtomicU32,
+
+    // Release time in ms
+    release: AtomicU32,
+
+    // True if the limiter is bypassed
+    bypass: AtomicBool,
+
+    // Gain reduction during the last block in dB, set by the audio thread
+    reduction: AtomicU32,
+}
+
+impl StripAtomics {
+    /// Creates atomics for a strip at volume 0, with every switch off
+    fn new() -> StripAtomics {
+        StripAtomics {
+            volume: AtomicU32::new(0),
+            mute: AtomicBool::new(false),
+            solo: AtomicBool::new(false),
+            invert: AtomicBool::new(false),
+            duck: AtomicBool::new(false),
+            meter: MeterAtomics::new(),
         }
     }
+}
 
-    // Grab volume levels
-    let mic_volume = f32::from_bits(atom!(mic_volume));
-    let line_volume = f32::from_bits(atom!(line_volume));
-    let song_volume = f32::from_bits(atom!(song_volume));
+impl DeckAtomics {
+    /// Creates atomics for a paused deck with no song loaded
+    fn new() -> DeckAtomics {
+        DeckAtomics {
+            song: AtomicUsize::new(usize::MAX),
+            timestamp: AtomicUsize::new(0),
+            max_timestamp: AtomicUsize::new(0),
+            paused: AtomicBool::new(true),
+            fading: AtomicBool::new(false),
+            loop_start: AtomicUsize::new(usize::MAX),
+            loop_end: AtomicUsize::new(usize::MAX),
+            looping: AtomicBool::new(false),
+            loop_exit: AtomicBool::new(false),
+            tempo: AtomicU32::new(1f32.to_bits()),
+            pitch: AtomicU32::new(0f32.to_bits()),
+        }
+    }
+}
+
+impl QueueAtomics {
+    /// Sets what decks do when their song ends
+    fn set_end_mode(&self, mode: EndMode) {
+        let i = EndMode::ALL.iter().position(|&m| m == mode).unwrap();
+        self.end_mode.store(i, Relaxed);
+    }
+}
 
-    if atom!(paused) {
-        // Music paused - mix in only microphone and line-in.
-        for i in 0..out.len() {
-            let m = mic_volume * mic[i];
-            let l = line_volume * line[i];
-            out[i] = (m + l) / 3.0;
+impl CrossfaderAtomics {
+    /// Returns the crossfader's curve
+    fn curve(&self) -> CrossfadeCurve {
+        CrossfadeCurve::ALL[self.curve.load(Relaxed)]
+    }
+
+    /// Sets the crossfader's curve
+    fn set_curve(&self, curve: CrossfadeCurve) {
+        let i = CrossfadeCurve::ALL
+            .iter()
+            .position(|&c| c == curve)
+            .unwrap();
+        self.curve.store(i, Relaxed);
+    }
+}
+
+impl MeterAtomics {
+    /// Creates a meter showing silence
+    fn new() -> MeterAtomics {
+        MeterAtomics {
+            peak: AtomicU32::new(0),
+            rms: AtomicU32::new(0),
         }
-    } else {
-        // Music is not paused - mix in all three sources
-        // Grab timestamp values
-        let timestamp = atom!(timestamp);
-        let max_timestamp = atom!(max_timestamp);
-
-        // Step = number of song data samples that will be copied into the output buffer.
-        // For most runs this will be equal to the length of the output, but if the music
-        // data runs out mid-buffer, then step will be less.
-        let step = out.len().min(max_timestamp - timestamp);
-
-        // Get reference to current song data
-        let i = music.song.unwrap();
-        let song = &music.songs[i].data;
-
-        // Copy song data mixed with mic and line
-        for i in 0..step {
-            let m = mic_volume * mic[i];
-            let l = line_volume * line[i];
-            let s = song_volume * song[timestamp + i];
-            out[i] = (m + l + s) / 3.0;
+    }
+
+    /// Returns the peak and RMS level, and resets the peak
+    fn read(&self) -> (f32, f32) {
+        (
+            f32::from_bits(self.peak.swap(0, Relaxed)),
+            f32::from_bits(self.rms.load(Relaxed)),
+        )
+    }
+}
+
+impl SharedAtomics {
+    /// Creates atomics for a paused mixer with no song loaded, decks going on to the next song of
+    /// the queue without overlap, the crossfader on deck A with a constant power curve, all strip
+    /// volumes at 0, the master at unity and the carts at unity.
+    fn new(sample_rate: u32, inputs: usize, carts: usize) -> SharedAtomics {
+        let inputs = (0..inputs).map(|_| StripAtomics::new()).collect();
+        let carts = (0..carts)
+            .map(|_| CartAtomics {
+                gain: AtomicU32::new(1f32.to_bits()),
+                remaining: AtomicUsize::new(0),
+            })
+            .collect();
+
+        let shared = SharedAtomics {
+            decks: Arc::new([DeckAtomics::new(), DeckAtomics::new(), DeckAtomics::new()]),
+            cue_volume: Arc::new(AtomicU32::new(1f32.to_bits())),
+            crossfader: Arc::new(CrossfaderAtomics {
+                position: AtomicU32::new(0f32.to_bits()),
+                curve: AtomicUsize::new(0),
+            }),
+            queue: Arc::new(QueueAtomics {
+                advanced: AtomicUsize::new(0),
+                end_mode: AtomicUsize::new(0),
+                overlap: AtomicUsize::new(0),
+            }),
+            sample_rate: Arc::new(AtomicUsize::new(sample_rate as usize)),
+            jack_rate: Arc::new(AtomicUsize::new(sample_rate as usize)),
+            inputs: Arc::new(inputs),
+            song_strip: Arc::new(StripAtomics::new()),
+            master_volume: Arc::new(AtomicU32::new(1f32.to_bits())),
+            limiter: Arc::new(LimiterAtomics {
+                ceiling: AtomicU32::new(0),
+                release: AtomicU32::new(0),
+                bypass: AtomicBool::new(false),
+                reduction: AtomicU32::new(0),
+            }),
+            ducker: Arc::new(DuckerAtomics {
+                enabled: AtomicBool::new(false),
+                threshold: AtomicU32::new(0),
+                depth: AtomicU32::new(0),
+                attack: AtomicU32::new(0),
+                hold: AtomicU32::new(0),
+                release: AtomicU32::new(0),
+                reduction: AtomicU32::new(0),
+            }),
+            output_meters: Arc::new([MeterAtomics::new(), MeterAtomics::new()]),
+            loudness: Arc::new(LoudnessAtomics {
+                momentary: AtomicU32::new(f32::NEG_INFINITY.to_bits()),
+                short_term: AtomicU32::new(f32::NEG_INFINITY.to_bits()),
+                integrated: AtomicU32::new(f32::NEG_INFINITY.to_bits()),
+                range: AtomicU32::new(0),
+                reset: AtomicBool::new(false),
+            }),
+            carts: Arc::new(carts),
+        };
+
+        shared.set_limiter(&LimiterConfig::default());
+        shared.set_ducker(&DuckerConfig::default());
+        shared.crossfader.set_curve(CrossfadeCurve::Power);
+        shared.queue.set_end_mode(EndMode::Next);
+        shared
+    }
+
+    /// Applies limiter settings from the config
+    fn set_limiter(&self, config: &LimiterConfig) {
+        self.limiter
+            .ceiling
+            .store(config.ceiling.to_bits(), Relaxed);
+        self.limiter
+            .release
+            .store(config.release.to_bits(), Relaxed);
+        self.limiter.bypass.store(config.bypass, Relaxed);
+    }
+
+    /// Applies ducker settings from the config
+    fn set_ducker(&self, config: &DuckerConfig) {
+        let ducker = &self.ducker;
+        ducker.enabled.store(config.enabled, Relaxed);
+        ducker.threshold.store(config.threshold.to_bits(), Relaxed);
+        ducker.depth.store(config.depth.to_bits(), Relaxed);
+        ducker.attack.store(config.attack.to_bits(), Relaxed);
+        ducker.hold.store(config.hold.to_bits(), Relaxed);
+        ducker.release.store(config.release.to_bits(), Relaxed);
+    }
+
+    /// Returns the atomics of a channel strip
+    fn strip(&self, strip: Strip) -> &StripAtomics {
+        match strip {
+            Strip::Input(i) => &self.inputs[i],
+            Strip::Music => &self.song_strip,
         }
+    }
+}
+
+/// Controls sent from the ui (or an offline script) to the mixing engine
+pub enum AudioControl {
+    /// Controls the transport of a deck by its index, or of the cue player with CUE
+    Deck(usize, DeckControl),
+
+    /// Replaces the play queue, as seen after the given number of songs were taken off it
+    Queue(Vec<usize>, usize),
+
+    /// Starts a cart, or restarts or stops it if it is playing
+    Cart(usize),
+
+    /// Fades out every playing cart
+    StopCarts,
 
-        // In the case that step < out.len(), fill the rest of out with a only a mix of mic and line
-        for i in step..out.len() {
-            let m = mic_volume * mic[i];
-            let l = line_volume * line[i];
-            out[i] = (m + l) / 3.0;
+    /// Replaces the markers of a song by its index
+    Markers(usize, Markers),
+}
+
+/// Transport controls of a playback deck
+pub enum DeckControl {
+    Paused(bool),
+    Load(usize),
+    JumpTo(usize),
+
+    /// Fades the song out over the given number of samples, then pauses
+    FadeOut(usize),
+
+    /// Sets the loop start to a sample
+    LoopStart(usize),
+
+    /// Sets the loop end to a sample and starts looping, jumping back right away if the deck is
+    /// already past it
+    LoopEnd(usize),
+
+    /// Turns looping between the loop points on or off
+    Looping(bool),
+
+    /// Leaves the loop the next time the deck reaches its end
+    ExitLoop,
+}
+
+/// Starts computing the waveform overview of every song in the background. Returns where the
+/// overviews appear as they are done.
+fn overviews(songs: &[Song]) -> Arc<Vec<OnceLock<Overview>>> {
+    let overviews: Arc<Vec<_>> = Arc::new(songs.iter().map(|_| OnceLock::new()).collect());
+
+    let data: Vec<_> = songs
+        .iter()
+        .map(|song| (song.data.clone(), song.channels))
+        .collect();
+    let done = overviews.clone();
+    thread::spawn(move || {
+        for ((data, channels), overview) in data.into_iter().zip(done.iter()) {
+            let _ = overview.set(Overview::new(&data, channels));
         }
+    });
 
-        // Advance timestamp, and pause if hitting the end of the song
-        let next_ts = timestamp + step;
-        atom!(timestamp = next_ts);
-        atom!(paused = next_ts == max_timestamp);
-    }
+    overviews
 }
 
-/// Load and decode all songs from the ./music folder
-fn load_songs() -> (BTreeMap<ImString, usize>, Vec<Song>) {
+/// Loads and decodes the carts' files, resampling them to sample_rate. Carts play at their own
+/// gain, so they are not normalized.
+fn load_carts(sample_rate: u32, carts: &[CartConfig]) -> Vec<Song> {
+    carts
+        .iter()
+        .map(|cart| {
+            println!("Loading cart {}", cart.file);
+            let data = fs::read(&cart.file)
+                .unwrap_or_else(|e| panic!("Could not read cart {}: {}", cart.file, e));
+            let mut song = Song::decode_mp3(data.as_slice());
+            song.resample(sample_rate);
+            song
+        })
+        .collect()
+}
+
+/// Load and decode all songs from the ./music folder, resampling them to sample_rate and
+/// measuring their loudness
+fn load_songs(
+    sample_rate: u32,
+    normalize: &NormalizeConfig,
+) -> (BTreeMap<ImString, usize>, Vec<Song>) {
+    let target = Some(normalize.target).filter(|_| normalize.enabled);
+
     // Vector of all file data
     let mut files: Vec<(ImString, PathBuf)> = Vec::new();
 
@@ -310,13 +1089,13 @@ fn load_songs() -> (BTreeMap<ImString, usize>, Vec<Song>) {
     for f in fs::read_dir("./music").unwrap() {
         let f = f.unwrap();
 
-        // Skip non files
-        if !f.file_type().unwrap().is_file() {
+        // Skip non files, and the markers kept next to songs
+        let path = f.path();
+        if !f.file_type().unwrap().is_file() || markers::is_sidecar(&path) {
             continue;
         }
 
-        // Grab file metadata 
-        let path = f.path();
+        // Grab file metadata
         let name = f.file_name();
 
         // Turn file name into an ImString.
@@ -333,8 +1112,15 @@ fn load_songs() -> (BTreeMap<ImString, usize>, Vec<Song>) {
     let songs: Vec<(ImString, Song)> = files
         .into_par_iter()
         .inspect(|(name, _)| println!("Loading song {}", name))
-        .map(|(name, path)| (name, fs::read(path).unwrap()))
-        .map(|(name, data)| (name, Song::decode_mp3(data.as_slice())))
+        .map(|(name, path)| {
+            let data = fs::read(&path).unwrap();
+            let tags = tags::read(&data, normalize.album);
+            let mut song = Song::decode_mp3(data.as_slice());
+            song.resample(sample_rate);
+            song.analyze(target, tags);
+            song.markers = Markers::load(&path, sample_rate);
+            (name, song)
+        })
         .collect();
 
     // Create map from song name to index into song vec
@@ -350,8 +1136,36 @@ fn load_songs() -> (BTreeMap<ImString, usize>, Vec<Song>) {
     (index_map, songs)
 }
 
-/// Utility function to convert a number of samples into minutes and seconds
-fn samples_to_minsec(samples: usize) -> (usize, usize) {
-    let seconds = samples / 48000;
+/// Converts a fader level in dB to a gain coefficient. Levels at or below MIN_DB are silent.
+pub fn db_to_gain(db: f32) -> f32 {
+    if db <= MIN_DB {
+        0.
+    } else {
+        10f32.powf(db / 20.)
+    }
+}
+
+/// Converts a gain coefficient to a level in dB, no lower than MIN_DB
+pub fn gain_to_db(gain: f32) -> f32 {
+    (20. * gain.log10()).max(MIN_DB)
+}
+
+/// Parses a time given either in seconds ("90.5") or minutes and seconds ("1:30.5").
+pub fn parse_time(s: &str) -> Option<f64> {
+    let seconds = match s.split_once(':') {
+        Some((m, s)) => m.parse::<u64>().ok()? as f64 * 60. + s.parse::<f64>().ok()?,
+        None => s.parse::<f64>().ok()?,
+    };
+
+    if seconds >= 0. {
+        Some(seconds)
+    } else {
+        None
+    }
+}
+
+/// Utility function to convert a number of samples at a sample rate into minutes and seconds
+pub fn samples_to_minsec(samples: usize, sample_rate: usize) -> (usize, usize) {
+    let seconds = samples / sample_rate;
     (seconds / 60, seconds % 60)
 }
@@ -0,0 +1,147 @@
+use super::smoothing::Smoothed;
+use super::song::Song;
+use super::CartAtomics;
+use crate::config::{CartConfig, CartMode};
+
+use std::sync::atomic::Ordering::Relaxed;
+
+/// Most carts that can sound at once. Triggering another cuts off the oldest.
+const MAX_VOICES: usize = 32;
+
+/// Plays the carts of the cart wall: short samples like station IDs and stingers, which sound
+/// over the song and the inputs without interrupting them. Every trigger starts a voice of its
+/// own, so a cart can overlap itself and other carts.
+pub struct CartPlayer {
+    // Sample of each cart, resampled to the engine's rate
+    carts: Vec<Song>,
+
+    // What triggering each cart does while it plays
+    modes: Vec<CartMode>,
+
+    // Gain of each cart as actually applied, and its value for the current sample
+    gains: Vec<Smoothed>,
+    current: Vec<f32>,
+
+    // Sounding voices, oldest first
+    voices: Vec<Voice>,
+
+    // Change of fade per sample when a voice is cut off
+    fade_step: f32,
+}
+
+struct Voice {
+    cart: usize,
+
+    // Next frame to play
+    pos: usize,
+
+    // Gain of the voice's fade out, from 1 down to 0, and whether it is fading out
+    fade: f32,
+    stopping: bool,
+}
+
+impl CartPlayer {
+    /// Creates a player of the given carts, which must already be resampled to the engine's
+    /// rate. Voices that are cut off fade out with fade_step per sample.
+    pub fn new(carts: Vec<Song>, config: &[CartConfig], fade_step: f32) -> CartPlayer {
+        CartPlayer {
+            modes: config.iter().map(|cart| cart.mode).collect(),
+            gains: carts.iter().map(|_| Smoothed::new(0.)).collect(),
+            current: vec![0.; carts.len()],
+            carts,
+            voices: Vec::with_capacity(MAX_VOICES),
+            fade_step,
+        }
+    }
+
+    /// Starts a cart. If it is already playing, restarts it or stops it depending on its mode.
+    pub fn trigger(&mut self, cart: usize) {
+        if cart >= self.carts.len() {
+            return;
+        }
+
+        let playing = self.voices.iter().any(|v| v.cart == cart && !v.stopping);
+        self.stop(cart);
+        if playing && self.modes[cart] == CartMode::Stop {
+            return;
+        }
+
+        if self.voices.len() == MAX_VOICES {
+            self.voices.remove(0);
+        }
+        self.voices.push(Voice {
+            cart,
+            pos: 0,
+            fade: 1.,
+            stopping: false,
+        });
+    }
+
+    /// Fades out every voice of a cart
+    pub fn stop(&mut self, cart: usize) {
+        for voice in self.voices.iter_mut().filter(|v| v.cart == cart) {
+            voice.stopping = true;
+        }
+    }
+
+    /// Fades out every voice
+    pub fn stop_all(&mut self) {
+        for voice in &mut self.voices {
+            voice.stopping = true;
+        }
+    }
+
+    /// Mixes the sounding voices into a block, and publishes the time left of each cart
+    pub fn process(
+        &mut self,
+        left: &mut [f32],
+        right: &mut [f32],
+        atomics: &[CartAtomics],
+        coef: f32,
+    ) {
+        for (gain, cart) in self.gains.iter_mut().zip(atomics) {
+            gain.set(f32::from_bits(cart.gain.load(Relaxed)));
+        }
+
+        let carts = &self.carts;
+        let current = &mut self.current;
+        let voices = &mut self.voices;
+        let fade_step = self.fade_step;
+
+        for i in 0..left.len() {
+            for (value, gain) in current.iter_mut().zip(self.gains.iter_mut()) {
+                *value = gain.next(coef);
+            }
+
+            for voice in voices.iter_mut() {
+                let cart = &carts[voice.cart];
+                if voice.pos >= cart.frames() {
+                    continue;
+                }
+
+                if voice.stopping {
+                    voice.fade = (voice.fade - fade_step).max(0.);
+                }
+
+                let (l, r) = cart.frame(voice.pos);
+                let gain = current[voice.cart] * voice.fade * voice.fade;
+                left[i] += gain * l;
+                right[i] += gain * r;
+                voice.pos += 1;
+            }
+        }
+
+        // Drop voices that have finished or faded out
+        voices.retain(|v| v.pos < carts[v.cart].frames() && v.fade > 0.);
+
+        for (c, cart) in atomics.iter().enumerate() {
+            let remaining = voices
+                .iter()
+                .filter(|v| v.cart == c && !v.stopping)
+                .map(|v| carts[c].frames() - v.pos)
+                .max()
+                .unwrap_or(0);
+            cart.remaining.store(remaining, Relaxed);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::f32::consts::FRAC_PI_2;
+
+/// How steeply a cut curve brings in a deck: it reaches full level this many times faster than
+/// a linear curve would
+const CUT_SLOPE: f32 = 16.;
+
+/// How the crossfader splits the level between the decks as it moves from deck A (0) to deck B
+/// (1)
+#[derive(Clone, Copy, PartialEq)]
+pub enum CrossfadeCurve {
+    /// Levels change in proportion, which dips by 6 dB in the middle
+    Linear,
+
+    /// Constant power: the summed level stays the same across the fade
+    Power,
+
+    /// Both decks play at full level except near the ends, for quick cuts
+    Cut,
+}
+
+impl CrossfadeCurve {
+    /// Every curve, in ui order
+    pub const ALL: [CrossfadeCurve; 3] = [
+        CrossfadeCurve::Linear,
+        CrossfadeCurve::Power,
+        CrossfadeCurve::Cut,
+    ];
+
+    /// Returns the name of the curve, as used in the ui and scripts
+    pub fn name(self) -> &'static str {
+        match self {
+            CrossfadeCurve::Linear => "linear",
+            CrossfadeCurve::Power => "power",
+            CrossfadeCurve::Cut => "cut",
+        }
+    }
+
+    /// Looks up a curve by its name
+    pub fn from_name(name: &str) -> Option<CrossfadeCurve> {
+        CrossfadeCurve::ALL
+            .iter()
+            .copied()
+            .find(|curve| curve.name() == name)
+    }
+
+    /// Returns the gains of deck A and deck B at a crossfader position from 0 to 1
+    pub fn gains(self, position: f32) -> (f32, f32) {
+        let x = position.clamp(0., 1.);
+        match self {
+            CrossfadeCurve::Linear => (1. - x, x),
+            CrossfadeCurve::Power => ((x * FRAC_PI_2).cos(), (x * FRAC_PI_2).sin()),
+            CrossfadeCurve::Cut => ((CUT_SLOPE * (1. - x)).min(1.), (CUT_SLOPE * x).min(1.)),
+        }
+    }
+}
@@ -0,0 +1,475 @@
+use super::queue::{EndMode, PlayQueue};
+use super::song::Song;
+use super::stretch::Stretcher;
+use super::{db_to_gain, DeckAtomics, DeckControl};
+
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering::Relaxed;
+
+/// A playback deck: plays one song at a time, with its own transport. Controls that would cut
+/// off the song wait until it has faded out, so play, pause, seek and load do not click. Songs
+/// play from their cue in to their cue out marker. When a song ends, the deck can go on with the
+/// next one on the exact next sample, or start it early so the two overlap with a crossfade.
+/// Between loop points set while it plays, the deck jumps back to the loop start the sample it
+/// reaches the loop end, with a short crossfade across the seam. Songs play through a
+/// time-stretcher, so the tempo and pitch can change while positions stay in frames of the song.
+pub struct Deck {
+    // Currently loaded song
+    song: Option<usize>,
+
+    // Controls waiting to be applied, oldest first
+    pending: VecDeque<DeckControl>,
+
+    // Gain of the transport fade, from 0 (silent) to 1
+    fade: f32,
+
+    // Change of fade per sample during the short fades around play, pause, seek and load
+    fade_step: f32,
+
+    // State for the block being played: whether the song plays, the next and last frame, the
+    // fade's target and change per sample, and the song's normalization gain. end is the song's
+    // cue out.
+    playing: bool,
+    pos: usize,
+    end: usize,
+    fade_target: f32,
+    block_fade_step: f32,
+    normalize: f32,
+
+    // Tempo and pitch ratios for the block, 1 for the song's own, and the stretcher playing the
+    // song at them
+    tempo: f64,
+    pitch: f64,
+    stretch: Stretcher,
+
+    // Whether the deck follows the end mode and queue, or always stops at the end of the song
+    queued: bool,
+
+    // What to do at the end of the song, and how many frames early to start the next one
+    end_mode: EndMode,
+    overlap: usize,
+
+    // Frames the song fades in over when it followed another with an overlap, 0 for none, and
+    // the frame the fade in starts at
+    ramp: usize,
+    ramp_start: usize,
+
+    // End of the previous song, fading out while the song fades in
+    tail: Option<Tail>,
+
+    // Whether the fades are a loop seam rather than one song following another. Both sides of a
+    // seam are much the same audio, so they fade linearly to add up to the same level.
+    seam: bool,
+
+    // Loop points, whether the deck loops between them, and whether it leaves the loop the next
+    // time it reaches the loop end
+    loop_start: Option<usize>,
+    loop_end: Option<usize>,
+    looping: bool,
+    loop_exit: bool,
+}
+
+/// The end of a song that is fading out under the next one
+struct Tail {
+    song: usize,
+    pos: usize,
+    end: usize,
+
+    // Frames the fade out takes
+    len: usize,
+
+    normalize: f32,
+
+    // Carries on from where the song was in the deck's stretcher
+    stretch: Stretcher,
+}
+
+impl Deck {
+    /// Creates a paused deck with no song loaded, for songs at the given sample rate. A queued
+    /// deck goes on to the next song as set by the end mode, others stop at the end of their
+    /// song.
+    pub fn new(fade_step: f32, sample_rate: u32, queued: bool) -> Deck {
+        Deck {
+            song: None,
+            pending: VecDeque::with_capacity(16),
+            fade: 0.,
+            fade_step,
+            playing: false,
+            pos: 0,
+            end: 0,
+            fade_target: 0.,
+            block_fade_step: fade_step,
+            normalize: 1.,
+            tempo: 1.,
+            pitch: 1.,
+            stretch: Stretcher::new(sample_rate),
+            queued,
+            end_mode: EndMode::Stop,
+            overlap: 0,
+            ramp: 0,
+            ramp_start: 0,
+            tail: None,
+            seam: false,
+            loop_start: None,
+            loop_end: None,
+            looping: false,
+            loop_exit: false,
+        }
+    }
+
+    /// Queues a control. Playing cancels a fade out that has not finished yet, and a jump
+    /// replaces one that is still waiting.
+    pub fn control(&mut self, c: DeckControl) {
+        match c {
+            DeckControl::Paused(false) => self
+                .pending
+                .retain(|c| !matches!(c, DeckControl::FadeOut(_))),
+            DeckControl::JumpTo(_) => self
+                .pending
+                .retain(|c| !matches!(c, DeckControl::JumpTo(_))),
+            _ => (),
+        }
+        self.pending.push_back(c);
+    }
+
+    /// Applies the queued controls that can be, and gets ready to play a block
+    pub fn begin(&mut self, songs: &[Song], atomics: &DeckAtomics, queue: &PlayQueue) {
+        use DeckControl::*;
+
+        // Pausing, loading and jumping while the song is audible have to wait for it to fade
+        // out first. Loading and jumping start the stretcher over.
+        let mut reset = false;
+        while let Some(c) = self.pending.front() {
+            let cuts = matches!(c, Paused(true) | Load(_) | JumpTo(_) | FadeOut(_));
+            if cuts && !atomics.paused.load(Relaxed) && self.fade > 0. {
+                break;
+            }
+
+            match self.pending.pop_front().unwrap() {
+                // Select song, and reset song playing variables. The song starts at its cue in.
+                Load(i) => {
+                    self.song = Some(i);
+                    self.ramp = 0;
+                    self.tail = None;
+                    self.clear_loop();
+                    reset = true;
+                    atomics.song.store(i, Relaxed);
+                    atomics.timestamp.store(songs[i].markers.start(), Relaxed);
+                    atomics.max_timestamp.store(songs[i].frames(), Relaxed);
+                    atomics.paused.store(true, Relaxed);
+                }
+
+                // Set paused status. Pausing always works, but unpausing only works when a song
+                // has been loaded.
+                Paused(p) => atomics.paused.store(p || self.song.is_none(), Relaxed),
+
+                // If requested time within range of song, timestamp = time. Jumping out of the
+                // loop stops looping.
+                JumpTo(n) => {
+                    if n <= atomics.max_timestamp.load(Relaxed) {
+                        let end = self
+                            .song
+                            .map_or(0, |i| songs[i].markers.end(songs[i].frames()));
+                        let n = n.min(end.saturating_sub(1));
+                        self.ramp = 0;
+                        self.tail = None;
+                        reset = true;
+                        atomics.timestamp.store(n, Relaxed);
+                        if !self.in_loop(n) {
+                            self.looping = false;
+                        }
+                    }
+                }
+
+                // The fade out is over
+                FadeOut(_) => atomics.paused.store(true, Relaxed),
+
+                // A loop start after the loop end removes the end
+                LoopStart(n) => {
+                    self.loop_start = Some(n);
+                    if self.loop_end.is_some_and(|end| end <= n) {
+                        self.loop_end = None;
+                        self.looping = false;
+                    }
+                }
+
+                // Only a loop end after the loop start makes a loop
+                LoopEnd(n) => {
+                    if self.loop_start.is_some_and(|start| start < n) {
+                        self.loop_end = Some(n);
+                        self.looping = true;
+                        self.loop_exit = false;
+                    }
+                }
+
+                Looping(on) => {
+                    self.looping = on && self.loop_end.is_some();
+                    self.loop_exit = false;
+                }
+
+                ExitLoop => self.loop_exit = self.looping,
+            }
+        }
+        let fading = self.pending.iter().any(|c| matches!(c, FadeOut(_)));
+        atomics.fading.store(fading, Relaxed);
+
+        self.playing = !atomics.paused.load(Relaxed);
+        self.pos = atomics.timestamp.load(Relaxed);
+        self.end = self
+            .song
+            .map_or(0, |i| songs[i].markers.end(songs[i].frames()));
+        self.normalize = self.song.map_or(1., |i| db_to_gain(songs[i].levels.gain));
+        self.tempo = f32::from_bits(atomics.tempo.load(Relaxed)) as f64;
+        let semitones = f32::from_bits(atomics.pitch.load(Relaxed)) as f64;
+        self.pitch = 2f64.powf(semitones / 12.);
+        if reset {
+            self.stretch.reset(self.pos, self.pitch);
+        }
+        self.end_mode = match self.queued {
+            true => queue.end_mode(),
+            false => EndMode::Stop,
+        };
+        self.overlap = queue.overlap();
+
+        // Fade out while a control waits for silence, over its own time for a fade out command,
+        // and fade in otherwise
+        let (target, step) = match self.pending.front() {
+            Some(&FadeOut(n)) => (0., 1. / n.max(1) as f32),
+            Some(_) => (0., self.fade_step),
+            None => (1., self.fade_step),
+        };
+        self.fade_target = target;
+        self.block_fade_step = step;
+    }
+
+    /// Returns the next frame of the block, faded and normalized. Silent while paused and once
+    /// the song runs out. Takes the next song from the queue when the end mode asks for it.
+    pub fn next(&mut self, songs: &[Song], queue: &mut PlayQueue) -> (f32, f32) {
+        if !self.playing {
+            return (0., 0.);
+        }
+
+        // Go on to the next song once this one is within the overlap of its end, before anything
+        // is played so a jump to the end still goes on. Songs shorter than twice the overlap
+        // overlap by half their length.
+        let start = songs[self.song.unwrap()].markers.start();
+        let overlap = self.overlap.min(self.end.saturating_sub(start) / 2);
+        if self.tail.is_none() && self.pos + overlap >= self.end {
+            let next = match self.end_mode {
+                EndMode::Stop => None,
+                EndMode::Next => queue.pop(),
+                EndMode::Loop => self.song,
+            };
+            if let Some(next) = next {
+                self.advance(songs, next);
+            }
+        }
+        if self.pos >= self.end && self.tail.is_none() {
+            return (0., 0.);
+        }
+
+        self.fade = if self.fade_target > self.fade {
+            (self.fade + self.block_fade_step).min(self.fade_target)
+        } else {
+            (self.fade - self.block_fade_step).max(self.fade_target)
+        };
+
+        // Squaring the fades makes long fades sound more even
+        let seam = self.seam;
+        let curve = |x: f32| if seam { x } else { x * x };
+        let (mut l, mut r) = (0., 0.);
+        if self.pos < self.end {
+            let ramp = if (self.ramp_start..self.ramp_start + self.ramp).contains(&self.pos) {
+                curve((self.pos - self.ramp_start) as f32 / self.ramp as f32)
+            } else {
+                1.
+            };
+            let song = &songs[self.song.unwrap()];
+            let (sl, sr) = self
+                .stretch
+                .next(song, &mut self.pos, self.tempo, self.pitch);
+            l += ramp * self.normalize * sl;
+            r += ramp * self.normalize * sr;
+        }
+
+        if let Some(tail) = &mut self.tail {
+            let gain = curve(tail.end.saturating_sub(tail.pos) as f32 / tail.len as f32);
+            let song = &songs[tail.song];
+            let (tl, tr) = tail
+                .stretch
+                .next(song, &mut tail.pos, self.tempo, self.pitch);
+            l += gain * tail.normalize * tl;
+            r += gain * tail.normalize * tr;
+            if tail.pos >= tail.end {
+                self.tail = None;
+            }
+        }
+
+        // Jump back at the loop end, or carry on past it when leaving the loop
+        if let (true, Some(start), Some(end)) = (self.looping, self.loop_start, self.loop_end) {
+            if self.pos >= end {
+                if self.loop_exit {
+                    self.looping = false;
+                    self.loop_exit = false;
+                } else {
+                    self.wrap(start, end);
+                }
+            }
+        }
+
+        let gain = self.fade * self.fade;
+        (gain * l, gain * r)
+    }
+
+    /// Starts the next song at its cue in, fading out what is left of the current one under it
+    fn advance(&mut self, songs: &[Song], next: usize) {
+        let remaining = self.end.saturating_sub(self.pos);
+        self.tail = match remaining {
+            0 => None,
+            _ => Some(Tail {
+                song: self.song.unwrap(),
+                pos: self.pos,
+                end: self.end,
+                len: remaining,
+                normalize: self.normalize,
+                stretch: self.stretch,
+            }),
+        };
+
+        let markers = songs[next].markers;
+        self.song = Some(next);
+        self.pos = markers.start();
+        self.end = markers.end(songs[next].frames());
+        self.normalize = db_to_gain(songs[next].levels.gain);
+        self.ramp = remaining;
+        self.ramp_start = self.pos;
+        self.seam = false;
+        self.stretch.reset(self.pos, self.pitch);
+        self.clear_loop();
+    }
+
+    /// Jumps from the loop end back to the loop start. Like the fades around transport changes,
+    /// the seam takes the length of a transport fade: the song past the loop end fades out while
+    /// the loop start fades in, linearly as both are much the same audio.
+    fn wrap(&mut self, start: usize, end: usize) {
+        let song = self.song.unwrap();
+        let seam = (1. / self.fade_step).round() as usize;
+        self.tail = match seam {
+            0 => None,
+            _ => Some(Tail {
+                song,
+                pos: end,
+                end: end + seam,
+                len: seam,
+                normalize: self.normalize,
+                stretch: self.stretch,
+            }),
+        };
+
+        self.pos = start;
+        self.stretch.reset(start, self.pitch);
+        self.ramp = seam;
+        self.ramp_start = start;
+        self.seam = true;
+    }
+
+    /// Returns true if frame n lies within the loop
+    fn in_loop(&self, n: usize) -> bool {
+        match (self.loop_start, self.loop_end) {
+            (Some(start), Some(end)) => (start..end).contains(&n),
+            _ => false,
+        }
+    }
+
+    /// Removes the loop points, as they belong to the song that was playing
+    fn clear_loop(&mut self) {
+        self.loop_start = None;
+        self.loop_end = None;
+        self.looping = false;
+        self.loop_exit = false;
+    }
+
+    /// Publishes the song, position and loop at the end of the block, and pauses if the song ran
+    /// out.
+    /// A paused song starts silent, to fade in when it plays again.
+    pub fn end(&mut self, songs: &[Song], atomics: &DeckAtomics) {
+        if self.playing {
+            if let Some(song) = self.song {
+                atomics.song.store(song, Relaxed);
+                atomics.max_timestamp.store(songs[song].frames(), Relaxed);
+            }
+            atomics.timestamp.store(self.pos, Relaxed);
+            let over = self.pos >= self.end && self.tail.is_none();
+            atomics.paused.store(over, Relaxed);
+        }
+
+        let point = |n: Option<usize>| n.unwrap_or(usize::MAX);
+        atomics.loop_start.store(point(self.loop_start), Relaxed);
+        atomics.loop_end.store(point(self.loop_end), Relaxed);
+        atomics.looping.store(self.looping, Relaxed);
+        atomics.loop_exit.store(self.loop_exit, Relaxed);
+
+        if atomics.paused.load(Relaxed) {
+            self.fade = 0.;
+            self.ramp = 0;
+            self.tail = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::offline::testing::*;
+
+    #[test]
+    fn queued_song_follows_without_a_gap() {
+        let script = "0 volume music 0\n0 load a\n0 queue b\n0 after next\n0 play\n1.5 end";
+        let songs = vec![song(0.5, |_| 0.25), song(0.5, |_| 0.5)];
+        let output = render(script, songs, &config(), &[]);
+        assert_level(&output, 0., 0.5, 0.25);
+        assert_level(&output, 0.5, 1., 0.5);
+        assert_level(&output, 1., 1.4, 0.);
+    }
+
+    #[test]
+    fn jump_to_the_end_goes_on_with_the_queue() {
+        let script = "0 volume music 0\n0 load a\n0 queue b\n0 after next\n0 play\n\
+                      0.1 jump 1\n0.5 end";
+        let songs = vec![song(1., |_| 0.25), song(1., |_| 0.5)];
+        let output = render(script, songs, &config(), &[]);
+        assert_level(&output, 0., 0.1, 0.25);
+        // The jump waits up to a block for the transport fade
+        assert_level(&output, 0.15, 0.5, 0.5);
+    }
+
+    #[test]
+    fn song_starts_over_at_its_end() {
+        let script = "0 volume music 0\n0 load a\n0 after loop\n0 play\n0.6 end";
+        let frames = at(0.25);
+        let songs = vec![song(0.25, |i| i as f32 / frames as f32)];
+        let output = render(script, songs, &config(), &[]);
+        for i in 0..frames {
+            assert_eq!(output[frames + i], output[i]);
+        }
+    }
+
+    #[test]
+    fn loop_jumps_back_at_its_end() {
+        let script = "0 volume music 0\n0 load a\n0 play\n0 loop in 0.1\n0 loop out 0.2\n0.5 end";
+        let songs = vec![song(1., |i| i as f32 / RATE as f32)];
+        let output = render(script, songs, &config(), &[]);
+        for t in [0.05, 0.15, 0.25, 0.35, 0.45] {
+            let expected = 0.1 + (t - 0.1) % 0.1;
+            assert!((output[at(t)] as f64 - expected).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn loop_seam_keeps_the_level() {
+        let script = "0 volume music 0\n0 load a\n0 play\n0 loop in 0.1\n0 loop out 0.2\n0.5 end";
+        let mut config = config();
+        config.transport_fade = 10.;
+        let output = render(script, vec![song(1., |_| 0.5)], &config, &[]);
+        assert_level(&output, 0.05, 0.5, 0.5);
+    }
+}
@@ -0,0 +1,100 @@
+use super::{db_to_gain, DuckerAtomics};
+
+use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+
+/// Time in ms the key level takes to fall to 1/e of a peak, so the ducker follows the loudness
+/// of the key rather than each cycle of its waveform
+const DETECTOR_MS: f32 = 10.;
+
+/// Sidechain ducker for the music: turns the music down by a set depth while the keying inputs,
+/// summed, are over the threshold. The reduction comes in over the attack time, stays for the
+/// hold time once the key is back under the threshold, then goes away over the release time.
+pub struct Ducker {
+    sample_rate: f32,
+
+    // Level of the key, following its peaks
+    level: f32,
+
+    // Samples left until the reduction starts to release
+    hold: usize,
+
+    // Gain reduction in dB, as a positive number
+    reduction: f32,
+}
+
+impl Ducker {
+    pub fn new(sample_rate: u32) -> Ducker {
+        Ducker {
+            sample_rate: sample_rate as f32,
+            level: 0.,
+            hold: 0,
+            reduction: 0.,
+        }
+    }
+
+    /// Turns a block of key samples, the sum of the keying inputs' levels after their faders,
+    /// into the gains to apply to the music, in place. While disabled the reduction releases and
+    /// the key is ignored. Publishes the largest gain reduction of the block.
+    pub fn process(&mut self, key: &mut [f32], atomics: &DuckerAtomics) {
+        let setting = |value: &AtomicU32| f32::from_bits(value.load(Relaxed));
+        let coef = |ms: f32| (-1000. / (ms * self.sample_rate)).exp();
+
+        let enabled = atomics.enabled.load(Relaxed);
+        let threshold = db_to_gain(setting(&atomics.threshold));
+        let depth = setting(&atomics.depth);
+        let attack = coef(setting(&atomics.attack));
+        let hold = (setting(&atomics.hold) / 1000. * self.sample_rate) as usize;
+        let release = coef(setting(&atomics.release));
+        let decay = coef(DETECTOR_MS);
+
+        let mut max_reduction: f32 = 0.;
+        for sample in key.iter_mut() {
+            self.level = sample.abs().max(self.level * decay);
+            if enabled && self.level > threshold {
+                self.hold = hold;
+            }
+
+            let target = if enabled && (self.level > threshold || self.hold > 0) {
+                depth
+            } else {
+                0.
+            };
+            self.hold = self.hold.saturating_sub(1);
+
+            let coef = if target > self.reduction {
+                attack
+            } else {
+                release
+            };
+            self.reduction = target + (self.reduction - target) * coef;
+            max_reduction = max_reduction.max(self.reduction);
+            *sample = db_to_gain(-self.reduction);
+        }
+
+        atomics.reduction.store(max_reduction.to_bits(), Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::db_to_gain;
+    use super::super::offline::testing::*;
+
+    #[test]
+    fn ducker_turns_the_music_down_by_its_depth() {
+        let script = "0 volume music 0\n0 volume mic_in 0\n0 load a\n0 play\n2 end";
+        let mut config = config();
+        config.ducker.enabled = true;
+        config.ducker.depth = 12.;
+        let mic: Vec<f32> = (0..at(2.))
+            .map(|i| if i < at(0.5) { 0. } else { 0.1 })
+            .collect();
+        let inputs = [mic];
+        let output = render(script, vec![song(2., |_| 0.25)], &config, &inputs);
+        let [mic] = inputs;
+
+        let music: Vec<f32> = output.iter().zip(&mic).map(|(s, m)| s - m).collect();
+        assert_level(&music, 0., 0.5, 0.25);
+        assert_level(&music, 1., 2., 0.25 * db_to_gain(-12.));
+    }
+}
@@ -0,0 +1,458 @@
+//! A small FLAC encoder for recordings. Uses fixed linear predictors with Rice coded residuals,
+//! which gets most of the compression of a full encoder at a fraction of the work.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// Frames per FLAC frame
+const BLOCK_SIZE: usize = 4096;
+
+/// Most channels a FLAC stream can have
+pub const MAX_CHANNELS: usize = 8;
+
+/// Highest Rice parameter of the 5 bit Rice coding method. 31 is reserved as the escape code.
+const MAX_RICE_PARAM: u32 = 30;
+
+/// Writes interleaved integer samples to a FLAC stream
+pub struct FlacWriter<W: Write + Seek> {
+    writer: W,
+    channels: usize,
+    sample_rate: u32,
+    bits: u32,
+
+    // Interleaved samples not yet encoded, less than one block
+    pending: Vec<i32>,
+
+    // Frames encoded so far, and the number of the next FLAC frame
+    total_frames: u64,
+    frame_number: u64,
+
+    // Smallest and largest encoded frame in bytes, for the stream info
+    min_frame_size: u32,
+    max_frame_size: u32,
+}
+
+impl<W: Write + Seek> FlacWriter<W> {
+    /// Starts a stream with the given format. bits is the size of the samples, up to 24.
+    pub fn new(mut writer: W, channels: usize, sample_rate: u32, bits: u32) -> io::Result<Self> {
+        if channels > MAX_CHANNELS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("FLAC holds at most {} channels", MAX_CHANNELS),
+            ));
+        }
+
+        writer.write_all(b"fLaC")?;
+
+        let mut flac = FlacWriter {
+            writer,
+            channels,
+            sample_rate,
+            bits,
+            pending: Vec::with_capacity(BLOCK_SIZE * channels),
+            total_frames: 0,
+            frame_number: 0,
+            min_frame_size: u32::MAX,
+            max_frame_size: 0,
+        };
+
+        // Written again with the final values when finishing
+        let info = flac.stream_info();
+        flac.writer.write_all(&info)?;
+        Ok(flac)
+    }
+
+    /// Adds interleaved samples. Encodes every full block.
+    pub fn write_samples(&mut self, samples: &[i32]) -> io::Result<()> {
+        let block_len = BLOCK_SIZE * self.channels;
+        for &s in samples {
+            self.pending.push(s);
+            if self.pending.len() == block_len {
+                self.encode_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes the remaining samples and rewrites the stream info with the totals. Returns the
+    /// writer.
+    pub fn finalize(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            self.encode_block()?;
+        }
+
+        let info = self.stream_info();
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&info)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Returns the STREAMINFO metadata block, as the last metadata block
+    fn stream_info(&self) -> Vec<u8> {
+        let mut bits = BitWriter::new();
+        bits.write(1, 1);
+        bits.write(0, 7);
+        bits.write(34, 24);
+
+        // Frame sizes are unknown until something is encoded
+        let min_frame = if self.max_frame_size == 0 {
+            0
+        } else {
+            self.min_frame_size
+        };
+        bits.write(BLOCK_SIZE as u64, 16);
+        bits.write(BLOCK_SIZE as u64, 16);
+        bits.write(min_frame as u64, 24);
+        bits.write(self.max_frame_size as u64, 24);
+        bits.write(self.sample_rate as u64, 20);
+        bits.write(self.channels as u64 - 1, 3);
+        bits.write(self.bits as u64 - 1, 5);
+        bits.write(self.total_frames, 36);
+
+        // No MD5 signature
+        bits.write(0, 64);
+        bits.write(0, 64);
+        bits.into_bytes()
+    }
+
+    /// Encodes the pending samples as one frame
+    fn encode_block(&mut self) -> io::Result<()> {
+        let frames = self.pending.len() / self.channels;
+        let mut bits = BitWriter::new();
+
+        // Frame header: sync code with fixed block size, block size and sample rate at the end
+        // of the header and in the stream info, independent channels
+        bits.write(0b11111111111110, 14);
+        bits.write(0, 2);
+        bits.write(0b0111, 4);
+        bits.write(0b0000, 4);
+        bits.write(self.channels as u64 - 1, 4);
+        bits.write(sample_size_code(self.bits), 3);
+        bits.write(0, 1);
+        write_utf8(&mut bits, self.frame_number);
+        bits.write(frames as u64 - 1, 16);
+        let crc = crc8(bits.bytes());
+        bits.write(crc as u64, 8);
+
+        let mut channel = Vec::with_capacity(frames);
+        for c in 0..self.channels {
+            channel.clear();
+            channel.extend(self.pending.iter().skip(c).step_by(self.channels));
+            encode_subframe(&mut bits, &channel, self.bits);
+        }
+
+        bits.pad();
+        let crc = crc16(bits.bytes());
+        bits.write(crc as u64, 16);
+
+        let bytes = bits.into_bytes();
+        self.writer.write_all(&bytes)?;
+
+        self.min_frame_size = self.min_frame_size.min(bytes.len() as u32);
+        self.max_frame_size = self.max_frame_size.max(bytes.len() as u32);
+        self.total_frames += frames as u64;
+        self.frame_number += 1;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+/// Encodes one channel of a block with the fixed predictor order that codes it smallest
+fn encode_subframe(bits: &mut BitWriter, samples: &[i32], sample_bits: u32) {
+    let max_order = 4.min(samples.len());
+
+    let (order, param, _) = (0..=max_order)
+        .map(|order| {
+            let (param, size) = rice_param(residuals(samples, order));
+            (order, param, size)
+        })
+        .min_by_key(|&(_, _, size)| size)
+        .unwrap();
+
+    // Subframe header: fixed predictor of the chosen order, no wasted bits
+    bits.write(0, 1);
+    bits.write(0b001000 | order as u64, 6);
+    bits.write(0, 1);
+
+    // Warm-up samples
+    for &s in &samples[..order] {
+        bits.write_signed(s as i64, sample_bits);
+    }
+
+    // Residual: 5 bit Rice parameters, a single partition
+    bits.write(1, 2);
+    bits.write(0, 4);
+    bits.write(param as u64, 5);
+    for r in residuals(samples, order) {
+        let u = zigzag(r);
+        bits.write_unary((u >> param) as u32);
+        bits.write(u & ((1 << param) - 1), param);
+    }
+}
+
+/// Returns the prediction residuals of a fixed predictor of the given order
+fn residuals(samples: &[i32], order: usize) -> impl Iterator<Item = i64> + '_ {
+    (order..samples.len()).map(move |i| {
+        let s = |k: usize| samples[i - k] as i64;
+        match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - 2 * s(1) + s(2),
+            3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+            _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+        }
+    })
+}
+
+/// Finds the best Rice parameter for the residuals. Returns it with the coded size in bits.
+fn rice_param(residuals: impl Iterator<Item = i64>) -> (u32, u64) {
+    let values: Vec<u64> = residuals.map(zigzag).collect();
+    (0..=MAX_RICE_PARAM)
+        .map(|k| {
+            let size = values.iter().map(|&u| (u >> k) + 1 + k as u64).sum();
+            (k, size)
+        })
+        .min_by_key(|&(_, size)| size)
+        .unwrap_or((0, 0))
+}
+
+/// Maps signed values to unsigned ones: 0, -1, 1, -2, ... to 0, 1, 2, 3, ...
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+fn sample_size_code(bits: u32) -> u64 {
+    match bits {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        24 => 0b110,
+        _ => 0b000,
+    }
+}
+
+/// Writes a frame number in the UTF-8 like coding FLAC uses
+fn write_utf8(bits: &mut BitWriter, n: u64) {
+    if n < 0x80 {
+        bits.write(n, 8);
+        return;
+    }
+
+    // Number of continuation bytes, each carrying 6 bits
+    let extra = match n {
+        0..=0x7FF => 1,
+        0x800..=0xFFFF => 2,
+        0x10000..=0x1FFFFF => 3,
+        0x200000..=0x3FFFFFF => 4,
+        0x4000000..=0x7FFFFFFF => 5,
+        _ => 6,
+    };
+    let lead_marker = (0xFF00u64 >> (extra + 1)) & 0xFF;
+    bits.write(lead_marker | (n >> (6 * extra)), 8);
+    for i in (0..extra).rev() {
+        bits.write(0x80 | ((n >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// Packs values into bytes, most significant bit first
+struct BitWriter {
+    bytes: Vec<u8>,
+
+    // Bits not yet making up a whole byte, and how many there are
+    acc: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            acc: 0,
+            count: 0,
+        }
+    }
+
+    /// Writes the low n bits of value, up to 32 at a time
+    fn write(&mut self, value: u64, n: u32) {
+        if n > 32 {
+            self.write(value >> 32, n - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+
+        let mask = if n == 0 { 0 } else { u64::MAX >> (64 - n) };
+        self.acc = (self.acc << n) | (value & mask);
+        self.count += n;
+        while self.count >= 8 {
+            self.count -= 8;
+            self.bytes.push((self.acc >> self.count) as u8);
+        }
+    }
+
+    /// Writes a signed value in n bits two's complement
+    fn write_signed(&mut self, value: i64, n: u32) {
+        self.write(value as u64, n);
+    }
+
+    /// Writes n zeros followed by a one
+    fn write_unary(&mut self, n: u32) {
+        for _ in 0..n / 32 {
+            self.write(0, 32);
+        }
+        self.write(1, n % 32 + 1);
+    }
+
+    /// Pads with zeros to a whole byte
+    fn pad(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+
+    /// The whole bytes written so far
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.pad();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// Encodes interleaved samples in one go and decodes them again with claxon, checking the
+    /// stream info on the way
+    fn round_trip(samples: &[i32], channels: usize, bits: u32) -> Vec<i32> {
+        let mut flac = FlacWriter::new(Cursor::new(Vec::new()), channels, 48000, bits).unwrap();
+        flac.write_samples(samples).unwrap();
+        let data = flac.finalize().unwrap().into_inner();
+
+        let mut reader = claxon::FlacReader::new(Cursor::new(data)).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.channels as usize, channels);
+        assert_eq!(info.bits_per_sample, bits);
+        assert_eq!(info.sample_rate, 48000);
+        assert_eq!(info.samples, Some((samples.len() / channels) as u64));
+        reader.samples().map(Result::unwrap).collect()
+    }
+
+    /// Returns n pseudo random samples of the given size
+    fn noise(n: usize, bits: u32) -> Vec<i32> {
+        let mut x = 0x2545_F491_4F6C_DD1Du64;
+        (0..n)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                (x >> (64 - bits)) as i32 - (1 << (bits - 1))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_samples_decode_exactly() {
+        for bits in [16, 24] {
+            let samples = noise(3 * BLOCK_SIZE * 2, bits);
+            assert_eq!(round_trip(&samples, 2, bits), samples);
+        }
+    }
+
+    #[test]
+    fn silence_decodes_exactly() {
+        for bits in [16, 24] {
+            let samples = vec![0; 2 * BLOCK_SIZE * 2];
+            assert_eq!(round_trip(&samples, 2, bits), samples);
+        }
+    }
+
+    #[test]
+    fn full_scale_decodes_exactly() {
+        for bits in [16, 24] {
+            let (min, max) = (-(1 << (bits - 1)), (1 << (bits - 1)) - 1);
+            let samples: Vec<i32> = (0..BLOCK_SIZE * 4)
+                .map(|i| if i / 6 % 2 == 0 { max } else { min })
+                .collect();
+            assert_eq!(round_trip(&samples, 4, bits), samples);
+        }
+    }
+
+    #[test]
+    fn spike_in_silence_decodes_exactly() {
+        // The residual of the spike is coded with a zero Rice parameter, so its quotient is far
+        // longer than 32 bits of unary
+        let mut samples = vec![0; BLOCK_SIZE];
+        samples[100] = (1 << 15) - 1;
+        samples[101] = -(1 << 15);
+        assert_eq!(round_trip(&samples, 1, 16), samples);
+    }
+
+    #[test]
+    fn partial_last_block_decodes_exactly() {
+        let samples = noise((2 * BLOCK_SIZE + 1000) * 3, 24);
+        assert_eq!(round_trip(&samples, 3, 24), samples);
+    }
+
+    #[test]
+    fn many_frames_decode_exactly() {
+        // Past frame 127 the frame number takes more than one byte
+        let samples: Vec<i32> = noise(300 * BLOCK_SIZE, 16).iter().map(|s| s >> 8).collect();
+        assert_eq!(round_trip(&samples, 1, 16), samples);
+    }
+
+    #[test]
+    fn frame_numbers_are_coded_like_utf8() {
+        let coded = |n: u64| {
+            let mut bits = BitWriter::new();
+            write_utf8(&mut bits, n);
+            bits.into_bytes()
+        };
+        assert_eq!(coded(0x7F), [0x7F]);
+        assert_eq!(coded(0x80), [0xC2, 0x80]);
+        assert_eq!(coded(0x7FF), [0xDF, 0xBF]);
+        assert_eq!(coded(0x800), [0xE0, 0xA0, 0x80]);
+        assert_eq!(coded(0xFFFF), [0xEF, 0xBF, 0xBF]);
+        assert_eq!(coded(0x10000), [0xF0, 0x90, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn crcs_match_the_check_values() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+}
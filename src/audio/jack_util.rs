@@ -1,8 +1,13 @@
-use jack::{Client, Control, ProcessScope};
+use jack::{Client, Control, Frames, ProcessScope};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::Arc;
+
+/// Closure type called by jack to process a buffer
+type ProcessFn = dyn Send + FnMut(&Client, &ProcessScope) -> Control;
 
 /// Process handler for jack. Could use jack::ClosureProcessHandler, but that would require
 /// putting FnMut trait bounds and lifetimes on every surrounding struct.
-pub struct JackBoxProcHandler(pub Box<dyn Send + FnMut(&Client, &ProcessScope) -> Control>);
+pub struct JackBoxProcHandler(pub Box<ProcessFn>);
 
 impl jack::ProcessHandler for JackBoxProcHandler {
     fn process(&mut self, client: &Client, ps: &ProcessScope) -> Control {
@@ -11,9 +16,24 @@ impl jack::ProcessHandler for JackBoxProcHandler {
 }
 
 /// Notification handler for jack. Jack library has examples on how to create this.
-#[derive(Default)]
 pub struct JackNotifs {
     xrun_count: usize,
+
+    // Rate the songs were resampled to when loading
+    song_rate: u32,
+
+    // Rate jack runs at, kept up to date with jack's
+    jack_rate: Arc<AtomicUsize>,
+}
+
+impl JackNotifs {
+    pub fn new(song_rate: u32, jack_rate: Arc<AtomicUsize>) -> JackNotifs {
+        JackNotifs {
+            xrun_count: 0,
+            song_rate,
+            jack_rate,
+        }
+    }
 }
 
 impl jack::NotificationHandler for JackNotifs {
@@ -31,4 +51,18 @@ impl jack::NotificationHandler for JackNotifs {
         println!("JACK: xrun occurred ({})", self.xrun_count);
         jack::Control::Continue
     }
+
+    fn sample_rate(&mut self, _: &Client, srate: Frames) -> Control {
+        self.jack_rate.store(srate as usize, Relaxed);
+
+        if srate != self.song_rate {
+            println!(
+                "JACK: sample rate changed to {}, but songs were loaded at {}. \
+                 Songs and carts are stopped until the mixer is restarted.",
+                srate, self.song_rate
+            );
+        }
+
+        Control::Continue
+    }
 }
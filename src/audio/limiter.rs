@@ -0,0 +1,161 @@
+use super::true_peak::{TruePeak, TP_DELAY};
+
+use std::collections::VecDeque;
+
+/// Time the limiter looks ahead, in seconds
+const LOOKAHEAD: f32 = 0.005;
+
+/// Look-ahead brickwall limiter for the stereo output bus. Detects true (inter-sample) peaks by
+/// 4x oversampling, and delays the audio so gain reduction is fully in place by the time a peak
+/// comes out, so the output never goes over the ceiling.
+pub struct Limiter {
+    sample_rate: f32,
+
+    // Look-ahead in samples
+    lookahead: usize,
+
+    // Detector of the true peaks of the input
+    true_peak: TruePeak,
+
+    // Sliding window minimum of the gain required by each sample, as (sample number, gain)
+    min_hold: VecDeque<(usize, f32)>,
+
+    // Gain after release smoothing
+    envelope: f32,
+
+    // Last lookahead envelope values and their sum, for the attack moving average
+    average: Vec<f32>,
+    average_sum: f64,
+
+    // Delay line of each channel
+    delay: [Vec<f32>; 2],
+
+    // Number of samples processed so far
+    n: usize,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u32) -> Limiter {
+        let lookahead = (LOOKAHEAD * sample_rate as f32) as usize;
+
+        let delay_len = Limiter::latency(sample_rate);
+
+        Limiter {
+            sample_rate: sample_rate as f32,
+            lookahead,
+            true_peak: TruePeak::new(),
+            min_hold: VecDeque::with_capacity(lookahead + 2),
+            envelope: 1.,
+            average: vec![1.; lookahead],
+            average_sum: lookahead as f64,
+            delay: [vec![0.; delay_len], vec![0.; delay_len]],
+            n: 0,
+        }
+    }
+
+    /// Number of samples the audio is delayed by. A sample leaves the delay line exactly when
+    /// the moving average has fully taken in the gain its peak required.
+    pub fn latency(sample_rate: u32) -> usize {
+        (LOOKAHEAD * sample_rate as f32) as usize + TP_DELAY
+    }
+
+    /// Limits a block in place so no true peak exceeds ceiling_db (dBTP). Gain recovers from
+    /// reduction with a time constant of release_ms. When bypassed, the audio is still delayed
+    /// so that switching does not cause a jump. Returns the largest gain reduction of the block
+    /// in dB, as a positive number.
+    pub fn process(
+        &mut self,
+        left: &mut [f32],
+        right: &mut [f32],
+        ceiling_db: f32,
+        release_ms: f32,
+        bypass: bool,
+    ) -> f32 {
+        let ceiling = 10f32.powf(ceiling_db / 20.);
+        let release = (-1. / (release_ms / 1000. * self.sample_rate)).exp();
+        let mut min_gain: f32 = 1.;
+
+        for i in 0..left.len() {
+            let input = [left[i], right[i]];
+
+            // Find the true peak around the sample TP_DELAY samples ago
+            let peak = self.true_peak.process(input[0], input[1]);
+
+            // Hold the lowest gain required over the look-ahead window, plus one sample so the
+            // neighbours of a peak sample are reduced too, as inter-sample peaks depend on them
+            let required = if peak > ceiling { ceiling / peak } else { 1. };
+            while matches!(self.min_hold.back(), Some(&(_, g)) if g >= required) {
+                self.min_hold.pop_back();
+            }
+            self.min_hold.push_back((self.n, required));
+            while matches!(self.min_hold.front(), Some(&(n, _)) if n + self.lookahead + 1 < self.n)
+            {
+                self.min_hold.pop_front();
+            }
+            let held = self.min_hold.front().unwrap().1;
+
+            // Reduce gain instantly, recover with the release time
+            self.envelope = if held < self.envelope {
+                held
+            } else {
+                held + release * (self.envelope - held)
+            };
+
+            // Smooth the attack with a moving average over the look-ahead window. Because the
+            // gain is held for the whole window, the average reaches it by the time the peak
+            // leaves the delay line.
+            let slot = self.n % self.lookahead;
+            self.average_sum += (self.envelope - self.average[slot]) as f64;
+            self.average[slot] = self.envelope;
+            let gain = if bypass {
+                1.
+            } else {
+                (self.average_sum / self.lookahead as f64) as f32
+            };
+            min_gain = min_gain.min(gain);
+
+            // Push through the delay line and apply the gain
+            let slot = self.n % self.delay[0].len();
+            let output = [self.delay[0][slot], self.delay[1][slot]];
+            self.delay[0][slot] = input[0];
+            self.delay[1][slot] = input[1];
+
+            // Clip whatever small overshoot the peak estimate might have missed
+            let clip = |s: f32| {
+                if bypass {
+                    s
+                } else {
+                    s.max(-ceiling).min(ceiling)
+                }
+            };
+            left[i] = clip(output[0] * gain);
+            right[i] = clip(output[1] * gain);
+
+            self.n += 1;
+        }
+
+        -20. * min_gain.log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::db_to_gain;
+    use super::super::offline::testing::*;
+
+    use std::f32::consts::PI;
+
+    #[test]
+    fn limiter_holds_the_ceiling() {
+        let script = "0 volume music 0\n0 load a\n0 play\n1 end";
+        let mut config = config();
+        config.limiter.bypass = false;
+        config.limiter.ceiling = -6.;
+        let sine = |i: usize| (2. * PI * 1000. * i as f32 / RATE as f32).sin();
+        let output = render(script, vec![song(1., sine)], &config, &[]);
+
+        let peak = output.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak <= db_to_gain(-6.) * 1.01, "peak of {}", peak);
+        assert!(peak > db_to_gain(-7.), "peak of {}", peak);
+    }
+}
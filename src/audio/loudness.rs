@@ -0,0 +1,315 @@
+use super::LoudnessAtomics;
+
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+
+/// Number of 100 ms sub-blocks in the momentary (400 ms) and short-term (3 s) windows
+const MOMENTARY_BLOCKS: usize = 4;
+const SHORT_TERM_BLOCKS: usize = 30;
+
+/// Loudness below which blocks are ignored, in LUFS
+const ABSOLUTE_GATE: f64 = -70.;
+
+/// Highest loudness kept track of by the histograms, in LUFS
+const HISTOGRAM_TOP: f64 = 5.;
+
+/// Width of a histogram bin, in LU
+const BIN_WIDTH: f64 = 0.1;
+
+/// Loudness meter following EBU R128 / ITU-R BS.1770 for a stereo signal. Measures momentary,
+/// short-term and integrated loudness and the loudness range. Blocks are counted in histograms,
+/// so it does not allocate and can run for any length of time.
+pub struct LoudnessMeter {
+    // K-weighting filters: a high shelf and a high pass, per channel
+    shelf: [Biquad; 2],
+    high_pass: [Biquad; 2],
+
+    // Length of a 100 ms sub-block in samples, and the samples and sum of squares so far in the
+    // current one
+    block_len: usize,
+    block_pos: usize,
+    block_sum: f64,
+
+    // Mean square of the last SHORT_TERM_BLOCKS sub-blocks, and the next one to overwrite
+    blocks: [f64; SHORT_TERM_BLOCKS],
+    block_slot: usize,
+
+    // Sub-blocks measured since the last reset
+    measured: usize,
+
+    // Histograms of the momentary blocks for integrated loudness, and of the short-term blocks
+    // for loudness range
+    momentary_hist: Histogram,
+    short_term_hist: Histogram,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32) -> LoudnessMeter {
+        let rate = sample_rate as f64;
+
+        // Filter design from BS.1770, recalculated for the sample rate
+        let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain / 20.);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1. + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2. * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        );
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1. + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1., -2., 1.],
+            [2. * (k * k - 1.) / a0, (1. - k / q + k * k) / a0],
+        );
+
+        LoudnessMeter {
+            shelf: [shelf.clone(), shelf],
+            high_pass: [high_pass.clone(), high_pass],
+            block_len: (sample_rate / 10) as usize,
+            block_pos: 0,
+            block_sum: 0.,
+            blocks: [0.; SHORT_TERM_BLOCKS],
+            block_slot: 0,
+            measured: 0,
+            momentary_hist: Histogram::new(),
+            short_term_hist: Histogram::new(),
+        }
+    }
+
+    /// Measures a block of the output and publishes the readings every 100 ms. Resets the
+    /// integrated loudness and loudness range first if the ui asked for it.
+    pub fn process(&mut self, left: &[f32], right: &[f32], atomics: &LoudnessAtomics) {
+        if atomics.reset.swap(false, Relaxed) {
+            self.measured = 0;
+            self.momentary_hist.clear();
+            self.short_term_hist.clear();
+        }
+
+        for (&l, &r) in left.iter().zip(right.iter()) {
+            if self.add(l, r) {
+                self.publish(atomics);
+            }
+        }
+    }
+
+    /// Measures one frame. Returns true if it completed a 100 ms sub-block.
+    pub fn add(&mut self, left: f32, right: f32) -> bool {
+        for (c, s) in [left, right].iter().enumerate() {
+            let weighted = self.high_pass[c].process(self.shelf[c].process(*s as f64));
+            self.block_sum += weighted * weighted;
+        }
+
+        self.block_pos += 1;
+        if self.block_pos == self.block_len {
+            self.end_block();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Finishes a 100 ms sub-block and updates the histograms
+    fn end_block(&mut self) {
+        self.blocks[self.block_slot] = self.block_sum / self.block_len as f64;
+        self.block_slot = (self.block_slot + 1) % SHORT_TERM_BLOCKS;
+        self.block_pos = 0;
+        self.block_sum = 0.;
+        self.measured += 1;
+
+        // Momentary blocks overlap by 75% and short-term blocks are taken every 100 ms, as
+        // soon as there is a full window of them
+        if self.measured >= MOMENTARY_BLOCKS {
+            self.momentary_hist.add(self.mean_square(MOMENTARY_BLOCKS));
+        }
+        if self.measured >= SHORT_TERM_BLOCKS {
+            self.short_term_hist
+                .add(self.mean_square(SHORT_TERM_BLOCKS));
+        }
+    }
+
+    /// Publishes the readings to the ui
+    fn publish(&self, atomics: &LoudnessAtomics) {
+        let momentary = self.mean_square(MOMENTARY_BLOCKS);
+        let short_term = self.mean_square(SHORT_TERM_BLOCKS);
+
+        let store = |atomic: &AtomicU32, lufs: f64| atomic.store((lufs as f32).to_bits(), Relaxed);
+        store(&atomics.momentary, loudness(momentary));
+        store(&atomics.short_term, loudness(short_term));
+        store(&atomics.integrated, self.integrated());
+        store(&atomics.range, self.range());
+    }
+
+    /// Mean square of the last n sub-blocks
+    fn mean_square(&self, n: usize) -> f64 {
+        (1..=n)
+            .map(|i| self.blocks[(self.block_slot + SHORT_TERM_BLOCKS - i) % SHORT_TERM_BLOCKS])
+            .sum::<f64>()
+            / n as f64
+    }
+
+    /// Integrated loudness in LUFS: the mean of the momentary blocks, gated 10 LU below the
+    /// mean of the blocks above the absolute gate
+    pub fn integrated(&self) -> f64 {
+        let hist = &self.momentary_hist;
+        let threshold = loudness(hist.mean_square(0)) - 10.;
+        loudness(hist.mean_square(hist.bin(threshold)))
+    }
+
+    /// Loudness range in LU: the spread between the 10th and 95th percentile of the short-term
+    /// blocks, gated 20 LU below the mean of the blocks above the absolute gate
+    fn range(&self) -> f64 {
+        let hist = &self.short_term_hist;
+        let threshold = loudness(hist.mean_square(0)) - 20.;
+        let first = hist.bin(threshold);
+
+        let total: u64 = hist.bins[first..].iter().map(|b| b.0).sum();
+        if total == 0 {
+            return 0.;
+        }
+
+        // Loudness of the bin holding the block at fraction p of the gated blocks
+        let percentile = |p: f64| {
+            let target = (p * total as f64) as u64;
+            let mut count = 0;
+            for (i, bin) in hist.bins.iter().enumerate().skip(first) {
+                count += bin.0;
+                if count > target {
+                    return Histogram::center(i);
+                }
+            }
+            Histogram::center(hist.bins.len() - 1)
+        };
+
+        percentile(0.95) - percentile(0.1)
+    }
+}
+
+/// Converts a K-weighted mean square, summed over the channels, to LUFS
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10. * mean_square.log10()
+}
+
+/// Counts of blocks by loudness, in bins of BIN_WIDTH from the absolute gate up
+struct Histogram {
+    // Number of blocks and the sum of their mean squares, per bin
+    bins: Vec<(u64, f64)>,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        let len = ((HISTOGRAM_TOP - ABSOLUTE_GATE) / BIN_WIDTH) as usize;
+        Histogram {
+            bins: vec![(0, 0.); len],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bins.iter_mut().for_each(|b| *b = (0, 0.));
+    }
+
+    /// Adds a block, unless it is below the absolute gate
+    fn add(&mut self, mean_square: f64) {
+        let lufs = loudness(mean_square);
+        if lufs > ABSOLUTE_GATE {
+            let i = self.bin(lufs);
+            let bin = &mut self.bins[i];
+            bin.0 += 1;
+            bin.1 += mean_square;
+        }
+    }
+
+    /// Index of the bin holding the given loudness. Louder blocks go in the top bin.
+    fn bin(&self, lufs: f64) -> usize {
+        (((lufs - ABSOLUTE_GATE) / BIN_WIDTH).max(0.) as usize).min(self.bins.len() - 1)
+    }
+
+    /// Loudness at the center of a bin
+    fn center(bin: usize) -> f64 {
+        ABSOLUTE_GATE + (bin as f64 + 0.5) * BIN_WIDTH
+    }
+
+    /// Mean square of the blocks from the given bin up. 0 if there are none.
+    fn mean_square(&self, from: usize) -> f64 {
+        let (count, sum) = self.bins[from..]
+            .iter()
+            .fold((0, 0.), |(n, s), b| (n + b.0, s + b.1));
+
+        if count == 0 {
+            0.
+        } else {
+            sum / count as f64
+        }
+    }
+}
+
+/// Biquad filter in direct form I
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad {
+            b,
+            a,
+            x: [0.; 2],
+            y: [0.; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// Feeds a 1 kHz sine to both channels, at a level in dBFS given for each sample
+    fn feed(meter: &mut LoudnessMeter, seconds: usize, level: impl Fn(usize) -> f64) {
+        for i in 0..seconds * RATE as usize {
+            let phase = 2. * PI * 1000. * i as f64 / RATE as f64;
+            let s = (10f64.powf(level(i) / 20.) * phase.sin()) as f32;
+            meter.add(s, s);
+        }
+    }
+
+    #[test]
+    fn sine_at_the_reference_level_reads_minus_23() {
+        let mut meter = LoudnessMeter::new(RATE);
+        feed(&mut meter, 20, |_| -23.);
+        let integrated = meter.integrated();
+        assert!((integrated + 23.).abs() < 0.1, "integrated {}", integrated);
+    }
+
+    #[test]
+    fn relative_gate_leaves_out_quiet_passages() {
+        // Half at -23 and half at -40, which is more than 10 LU below the ungated mean of
+        // about -26
+        let mut meter = LoudnessMeter::new(RATE);
+        let half = 20 * RATE as usize;
+        feed(&mut meter, 40, |i| if i < half { -23. } else { -40. });
+        let integrated = meter.integrated();
+        assert!((integrated + 23.).abs() < 0.1, "integrated {}", integrated);
+    }
+}
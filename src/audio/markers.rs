@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Ending of the file a song's markers are kept in, next to the song: song.mp3 has its markers
+/// in song.mp3.markers.toml
+const SIDECAR_SUFFIX: &str = ".markers.toml";
+
+/// A point in a song the operator marked
+#[derive(Clone, Copy, PartialEq)]
+pub enum Marker {
+    /// Where the song starts playing
+    CueIn,
+
+    /// Where the vocals start, so hosts can talk until then
+    IntroEnd,
+
+    /// Where the vocals end, so hosts can talk from then on
+    OutroStart,
+
+    /// Where the song stops playing, or the next one takes over
+    CueOut,
+}
+
+impl Marker {
+    /// Every marker, in the order they come in a song
+    pub const ALL: [Marker; 4] = [
+        Marker::CueIn,
+        Marker::IntroEnd,
+        Marker::OutroStart,
+        Marker::CueOut,
+    ];
+
+    /// Returns the name of the marker as shown in the ui
+    pub fn name(self) -> &'static str {
+        match self {
+            Marker::CueIn => "Cue in",
+            Marker::IntroEnd => "Intro end",
+            Marker::OutroStart => "Outro start",
+            Marker::CueOut => "Cue out",
+        }
+    }
+}
+
+/// The markers of a song, as frames from its start. Unset markers fall back to the start and
+/// end of the song, or are not shown for the intro and outro.
+#[derive(Clone, Copy, Default)]
+pub struct Markers {
+    frames: [Option<usize>; 4],
+}
+
+/// The markers as saved in a song's sidecar file, in seconds so they do not depend on the
+/// sample rate
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct MarkerFile {
+    cue_in: Option<f64>,
+    intro_end: Option<f64>,
+    outro_start: Option<f64>,
+    cue_out: Option<f64>,
+}
+
+impl Markers {
+    /// Returns the frame of a marker, or None if it is not set
+    pub fn get(&self, marker: Marker) -> Option<usize> {
+        self.frames[marker as usize]
+    }
+
+    /// Sets a marker to a frame, or clears it with None. A frame that would put the marker out of
+    /// order with the others is refused, and false returned.
+    pub fn set(&mut self, marker: Marker, frame: Option<usize>) -> bool {
+        if frame.is_some_and(|frame| !self.allows(marker, frame)) {
+            return false;
+        }
+
+        self.frames[marker as usize] = frame;
+        true
+    }
+
+    /// Returns true if a marker can be set to a frame and stay in order with the markers that are
+    /// set: cue in before intro end, intro end no later than outro start, and outro start before
+    /// cue out
+    pub fn allows(&self, marker: Marker, frame: usize) -> bool {
+        let i = marker as usize;
+        self.frames.iter().enumerate().all(|(j, other)| {
+            let other = match other {
+                Some(other) if j != i => *other,
+                _ => return true,
+            };
+            // The intro may end right where the outro starts
+            let touching =
+                (i.min(j), i.max(j)) == (Marker::IntroEnd as usize, Marker::OutroStart as usize);
+            match (j < i, touching) {
+                (true, true) => other <= frame,
+                (true, false) => other < frame,
+                (false, true) => frame <= other,
+                (false, false) => frame < other,
+            }
+        })
+    }
+
+    /// Returns the frame the song starts playing at
+    pub fn start(&self) -> usize {
+        self.get(Marker::CueIn).unwrap_or(0)
+    }
+
+    /// Returns the frame the song stops playing at, given its length in frames
+    pub fn end(&self, frames: usize) -> usize {
+        self.get(Marker::CueOut)
+            .map_or(frames, |end| end.min(frames))
+    }
+
+    /// Reads the markers of the song at path, at the given sample rate. A missing sidecar file
+    /// means no markers, and an invalid one is reported and ignored. Markers out of order with the
+    /// ones before them are reported and left unset.
+    pub fn load(path: &Path, sample_rate: u32) -> Markers {
+        let text = match fs::read_to_string(sidecar_path(path)) {
+            Ok(text) => text,
+            Err(_) => return Markers::default(),
+        };
+        let file: MarkerFile = match toml::from_str(&text) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("Invalid markers for {}: {}", path.display(), e);
+                return Markers::default();
+            }
+        };
+
+        let frame =
+            |seconds: Option<f64>| seconds.map(|s| (s * sample_rate as f64).round() as usize);
+        let frames = [
+            frame(file.cue_in),
+            frame(file.intro_end),
+            frame(file.outro_start),
+            frame(file.cue_out),
+        ];
+        let mut markers = Markers::default();
+        for (&marker, frame) in Marker::ALL.iter().zip(frames) {
+            if !markers.set(marker, frame) {
+                println!("{} of {} is out of order", marker.name(), path.display());
+            }
+        }
+        markers
+    }
+
+    /// Writes the markers of the song at path to its sidecar file, or removes the file when no
+    /// marker is set. Failures are reported, as the markers still apply until the mixer closes.
+    pub fn save(&self, path: &Path, sample_rate: u32) {
+        let sidecar = sidecar_path(path);
+        if self.frames.iter().all(Option::is_none) {
+            if sidecar.exists() {
+                if let Err(e) = fs::remove_file(&sidecar) {
+                    println!("Could not remove {}: {}", sidecar.display(), e);
+                }
+            }
+            return;
+        }
+
+        let seconds = |marker| self.get(marker).map(|f| f as f64 / sample_rate as f64);
+        let file = MarkerFile {
+            cue_in: seconds(Marker::CueIn),
+            intro_end: seconds(Marker::IntroEnd),
+            outro_start: seconds(Marker::OutroStart),
+            cue_out: seconds(Marker::CueOut),
+        };
+        let text = toml::to_string(&file).unwrap();
+        if let Err(e) = fs::write(&sidecar, text) {
+            println!("Could not save {}: {}", sidecar.display(), e);
+        }
+    }
+}
+
+/// Returns true if path is the sidecar file of a song rather than a song
+pub fn is_sidecar(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(SIDECAR_SUFFIX)
+}
+
+/// Returns the path of the sidecar file of the song at path
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(SIDECAR_SUFFIX);
+    sidecar.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// Returns a path for a song in a directory of its own, which is removed first
+    fn song_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("markers_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("song.mp3")
+    }
+
+    #[test]
+    fn markers_stay_in_order() {
+        for (i, &first) in Marker::ALL.iter().enumerate() {
+            for &second in &Marker::ALL[i + 1..] {
+                let touching = (first, second) == (Marker::IntroEnd, Marker::OutroStart);
+
+                // The later marker set after the earlier one
+                let mut markers = Markers::default();
+                assert!(markers.set(first, Some(100)));
+                assert!(!markers.allows(second, 99));
+                assert_eq!(markers.allows(second, 100), touching);
+                assert!(markers.allows(second, 101));
+                assert!(!markers.set(second, Some(99)));
+                assert_eq!(markers.get(second), None);
+
+                // The earlier marker set after the later one
+                let mut markers = Markers::default();
+                assert!(markers.set(second, Some(100)));
+                assert!(markers.allows(first, 99));
+                assert_eq!(markers.allows(first, 100), touching);
+                assert!(!markers.allows(first, 101));
+                assert!(!markers.set(first, Some(101)));
+                assert_eq!(markers.get(first), None);
+
+                // A refused frame leaves the marker as it was, and clearing is always allowed
+                assert!(markers.set(first, Some(50)));
+                assert!(!markers.set(first, Some(150)));
+                assert_eq!(markers.get(first), Some(50));
+                assert!(markers.set(second, None));
+                assert!(markers.set(first, Some(150)));
+            }
+        }
+    }
+
+    #[test]
+    fn save_and_load_keep_every_frame() {
+        let path = song_path("round_trip");
+        let mut markers = Markers::default();
+        for (&marker, frame) in Marker::ALL.iter().zip([12345, 99999, 99999, 4_800_001]) {
+            assert!(markers.set(marker, Some(frame)));
+        }
+        markers.save(&path, RATE);
+
+        let loaded = Markers::load(&path, RATE);
+        for &marker in &Marker::ALL {
+            assert_eq!(loaded.get(marker), markers.get(marker), "{}", marker.name());
+        }
+
+        // Clearing every marker removes the file
+        Markers::default().save(&path, RATE);
+        assert!(!sidecar_path(&path).exists());
+        assert!(Markers::load(&path, RATE).get(Marker::CueIn).is_none());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn load_leaves_out_of_order_markers_unset() {
+        let path = song_path("out_of_order");
+        let text = "cue_in = 10.0\nintro_end = 5.0\noutro_start = 20.0\ncue_out = 15.0\n";
+        fs::write(sidecar_path(&path), text).unwrap();
+
+        let markers = Markers::load(&path, RATE);
+        assert_eq!(markers.get(Marker::CueIn), Some(10 * RATE as usize));
+        assert_eq!(markers.get(Marker::IntroEnd), None);
+        assert_eq!(markers.get(Marker::OutroStart), Some(20 * RATE as usize));
+        assert_eq!(markers.get(Marker::CueOut), None);
+
+        // An invalid file is ignored
+        fs::write(sidecar_path(&path), "cue_in = \"soon\"").unwrap();
+        assert!(Markers::load(&path, RATE).get(Marker::CueIn).is_none());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
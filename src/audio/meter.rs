@@ -0,0 +1,45 @@
+use super::MeterAtomics;
+
+use std::sync::atomic::Ordering::Relaxed;
+
+/// Time constant of the RMS average, in seconds, similar to a VU meter's integration time
+const RMS_TIME: f32 = 0.3;
+
+/// Level meter of one signal. Samples are added one at a time, and the levels published to the
+/// ui once per block.
+pub struct Meter {
+    // Highest absolute sample since the last publish
+    peak: f32,
+
+    // Exponentially averaged square of the samples, and its per-sample coefficient
+    mean_square: f32,
+    coef: f32,
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32) -> Meter {
+        Meter {
+            peak: 0.,
+            mean_square: 0.,
+            coef: (-1. / (RMS_TIME * sample_rate as f32)).exp(),
+        }
+    }
+
+    /// Adds one frame. Mono signals pass the same sample as left and right.
+    pub fn add(&mut self, left: f32, right: f32) {
+        self.peak = self.peak.max(left.abs()).max(right.abs());
+        let square = (left * left + right * right) / 2.;
+        self.mean_square = square + self.coef * (self.mean_square - square);
+    }
+
+    /// Publishes the levels of the block to the ui. The peak is kept until the ui reads it, so
+    /// peaks between two ui frames are not missed.
+    pub fn publish(&mut self, atomics: &MeterAtomics) {
+        // Bits of non-negative floats order the same as the floats
+        atomics.peak.fetch_max(self.peak.to_bits(), Relaxed);
+        atomics
+            .rms
+            .store(self.mean_square.sqrt().to_bits(), Relaxed);
+        self.peak = 0.;
+    }
+}
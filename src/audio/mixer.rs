@@ -0,0 +1,407 @@
+use super::cart::CartPlayer;
+use super::deck::Deck;
+use super::ducker::Ducker;
+use super::limiter::Limiter;
+use super::loudness::LoudnessMeter;
+use super::meter::Meter;
+use super::queue::PlayQueue;
+use super::recorder::RecordTap;
+use super::smoothing::{smoothing_coef, Smoothed};
+use super::song::Song;
+use super::{db_to_gain, AudioControl, SharedAtomics, StripAtomics, CUE, DECKS};
+use crate::config::Config;
+
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// Most frames mixed at a time. Longer blocks are mixed in pieces, so the buffers reserved for a
+/// block are never outgrown on the audio thread.
+const MAX_BLOCK: usize = 8192;
+
+/// The mixing engine. Owns the song data and mixes the inputs with the songs playing on the
+/// decks. Also plays the cue bus, where songs are previewed apart from the output.
+/// It knows nothing about jack, so it can be driven by the jack process callback or by the
+/// offline renderer alike.
+pub struct Mixer {
+    music: MusicThread,
+}
+
+impl Mixer {
+    /// Creates a mixer with both decks paused and no song loaded, running at sample_rate with the
+    /// input strips, carts and limiter settings of the config. The songs and carts must already
+    /// be resampled to that rate. Also returns the sender and atomics used to control the mixer
+    /// from another thread, and the receiver of replaced play queues, which that thread should
+    /// free.
+    pub fn new(
+        songs: Vec<Song>,
+        carts: Vec<Song>,
+        sample_rate: u32,
+        config: &Config,
+    ) -> (
+        Mixer,
+        Sender<AudioControl>,
+        SharedAtomics,
+        Receiver<VecDeque<usize>>,
+    ) {
+        let (ac_send, ac_recv) = channel();
+        let (old_queues_send, old_queues) = channel();
+        let inputs = config.inputs.len();
+        let shared = SharedAtomics::new(sample_rate, inputs, config.carts.len());
+        shared.set_limiter(&config.limiter);
+        shared.set_ducker(&config.ducker);
+        for (atomics, input) in shared.inputs.iter().zip(&config.inputs) {
+            atomics.duck.store(input.duck, Relaxed);
+        }
+        for (atomics, cart) in shared.carts.iter().zip(&config.carts) {
+            atomics.gain.store(db_to_gain(cart.gain).to_bits(), Relaxed);
+        }
+        let fade_step = 1. / (config.transport_fade / 1000. * sample_rate as f32).max(1.);
+
+        let music = MusicThread {
+            ac_recv,
+            songs,
+            shared: shared.clone(),
+            decks: [
+                Deck::new(fade_step, sample_rate, true),
+                Deck::new(fade_step, sample_rate, true),
+            ],
+            cue: Deck::new(fade_step, sample_rate, false),
+            cue_gain: Smoothed::new(1.),
+            crossfade_gains: [Smoothed::new(1.), Smoothed::new(0.)],
+            queue: PlayQueue::new(shared.queue.clone(), old_queues_send),
+            limiter: Limiter::new(sample_rate),
+            ducker: Ducker::new(sample_rate),
+            duck_gains: Vec::with_capacity(MAX_BLOCK),
+            smoothing: smoothing_coef(config.smoothing, sample_rate),
+            carts: CartPlayer::new(carts, &config.carts, fade_step),
+            input_meters: (0..inputs).map(|_| Meter::new(sample_rate)).collect(),
+            song_meter: Meter::new(sample_rate),
+            output_meters: [Meter::new(sample_rate), Meter::new(sample_rate)],
+            loudness: LoudnessMeter::new(sample_rate),
+            record_tap: None,
+            input_gains: (0..inputs).map(|_| Smoothed::new(0.)).collect(),
+            song_gain: Smoothed::new(0.),
+            master_gain: Smoothed::new(1.),
+        };
+
+        (Mixer { music }, ac_send, shared, old_queues)
+    }
+
+    /// Sends the output to a recorder
+    pub fn set_record_tap(&mut self, tap: RecordTap) {
+        self.music.record_tap = Some(tap);
+    }
+
+    /// Mixes one block of audio into the left and right outputs, and the cue player into the
+    /// mono cue output. inputs yields the block of each mono input strip in order, which are
+    /// spread to both sides. All slices must be the same length.
+    pub fn process<'a>(
+        &mut self,
+        left: &mut [f32],
+        right: &mut [f32],
+        cue: &mut [f32],
+        inputs: impl Iterator<Item = &'a [f32]> + Clone,
+    ) {
+        for start in (0..left.len()).step_by(MAX_BLOCK) {
+            let end = (start + MAX_BLOCK).min(left.len());
+            let (l, r) = (&mut left[start..end], &mut right[start..end]);
+            let inputs = inputs.clone().map(|input| &input[start..end]);
+            audio_callback(l, r, inputs, &mut self.music);
+            cue_callback(&mut cue[start..end], &mut self.music);
+        }
+    }
+}
+
+struct MusicThread {
+    // Receiver for controls from the ui thread
+    ac_recv: Receiver<AudioControl>,
+
+    // Song data that can be played given an index which is calculated in the ui thread
+    songs: Vec<Song>,
+
+    // Atomics shared between audio and ui threads
+    shared: SharedAtomics,
+
+    // Playback decks, mixed through the crossfader
+    decks: [Deck; DECKS],
+
+    // Player of the cue bus, and its gain as actually applied
+    cue: Deck,
+    cue_gain: Smoothed,
+
+    // Gains of the decks from the crossfader as actually applied
+    crossfade_gains: [Smoothed; DECKS],
+
+    // Songs the decks go on to as their songs end
+    queue: PlayQueue,
+
+    // Limiter on the output bus
+    limiter: Limiter,
+
+    // Ducker of the music, and the block of its key which it turns into the music's gains
+    ducker: Ducker,
+    duck_gains: Vec<f32>,
+
+    // Per-sample coefficient of the gain smoothers
+    smoothing: f32,
+
+    // Gains as actually applied, gliding towards the atomics' values
+    input_gains: Vec<Smoothed>,
+    song_gain: Smoothed,
+    master_gain: Smoothed,
+
+    // Voices of the cart wall, playing over the songs
+    carts: CartPlayer,
+
+    // Level meters of each input and the music (both decks after the crossfader) before their
+    // faders, and of the left and right outputs
+    input_meters: Vec<Meter>,
+    song_meter: Meter,
+    output_meters: [Meter; 2],
+
+    // Loudness meter of the output
+    loudness: LoudnessMeter,
+
+    // Feeds the output to the recorder, if there is one
+    record_tap: Option<RecordTap>,
+}
+
+/// Mixes a block. The summing law is plain unity-gain summing: each source is multiplied by its
+/// strip gain, the products are added together, and the sum is multiplied by the master gain.
+/// So a single source with its strip and the master at 0 dB reaches the output unchanged.
+/// Mono inputs are sent to both sides at full level, with no pan law. Gains are smoothed per
+/// sample, so they change without zipper noise, and the songs fade in and out around transport
+/// changes so they do not click. The decks are summed through the crossfader into the music
+/// strip, which the ducker turns down while the inputs keying it are live.
+fn audio_callback<'a>(
+    left: &mut [f32],
+    right: &mut [f32],
+    inputs: impl Iterator<Item = &'a [f32]>,
+    music: &mut MusicThread,
+) {
+    use AudioControl::*;
+
+    // A macro for easier working with atomics in the MusicThread struct
+    #[rustfmt::skip]
+    macro_rules! atom {
+        ($name:ident)             => { music.shared.$name.load(Relaxed)        };
+        ($name:ident = $val:expr) => { music.shared.$name.store($val, Relaxed) };
+    }
+
+    // Pass new controls on. Carts play over the songs, so they never wait.
+    for c in music.ac_recv.try_iter() {
+        match c {
+            Deck(CUE, c) => music.cue.control(c),
+            Deck(deck, c) => music.decks[deck].control(c),
+            Queue(songs, seen) => music.queue.set(songs, seen),
+            Cart(i) => music.carts.trigger(i),
+            StopCarts => music.carts.stop_all(),
+            Markers(i, markers) => music.songs[i].markers = markers,
+        }
+    }
+
+    // Apply the controls the decks can
+    for (deck, atomics) in music.decks.iter_mut().zip(music.shared.decks.iter()) {
+        deck.begin(&music.songs, atomics, &music.queue);
+    }
+
+    if let Some(tap) = &mut music.record_tap {
+        tap.begin(left.len());
+    }
+
+    // While any strip is soloed, only soloed strips are heard
+    let any_solo = music.shared.song_strip.solo.load(Relaxed)
+        || music.shared.inputs.iter().any(|s| s.solo.load(Relaxed));
+
+    // Mix in every input strip. Inputs are mono, so they go to both sides equally. The strips
+    // keying the ducker are summed after their faders, so a closed mic does not duck.
+    let coef = music.smoothing;
+    left.iter_mut().for_each(|s| *s = 0.);
+    music.duck_gains.clear();
+    music.duck_gains.resize(left.len(), 0.);
+    for ((((i, input), strip), gain), meter) in inputs
+        .enumerate()
+        .zip(music.shared.inputs.iter())
+        .zip(music.input_gains.iter_mut())
+        .zip(music.input_meters.iter_mut())
+    {
+        gain.set(strip_gain(strip, any_solo));
+        let duck = strip.duck.load(Relaxed);
+        for i in 0..left.len() {
+            let sample = gain.next(coef) * input[i];
+            left[i] += sample;
+            if duck {
+                music.duck_gains[i] += sample;
+            }
+            meter.add(input[i], input[i]);
+        }
+        meter.publish(&strip.meter);
+
+        if let Some(tap) = &mut music.record_tap {
+            tap.input(i, input);
+        }
+    }
+    right.copy_from_slice(left);
+
+    // Turn the music down while the keying strips are live
+    music
+        .ducker
+        .process(&mut music.duck_gains, &music.shared.ducker);
+
+    music
+        .song_gain
+        .set(strip_gain(&music.shared.song_strip, any_solo));
+
+    // Gains of the decks from the crossfader
+    let crossfader = &music.shared.crossfader;
+    let position = f32::from_bits(crossfader.position.load(Relaxed));
+    let (gain_a, gain_b) = crossfader.curve().gains(position);
+    let [xfade_a, xfade_b] = &mut music.crossfade_gains;
+    xfade_a.set(gain_a);
+    xfade_b.set(gain_b);
+
+    // Mix in the decks through the crossfader. The meter and recorder see the music before its
+    // fader. The songs and carts can only play at the rate they were loaded at, so they are
+    // silent while jack runs at another, leaving the inputs on air.
+    let stopped = atom!(jack_rate) != atom!(sample_rate);
+    let [deck_a, deck_b] = &mut music.decks;
+    for i in 0..left.len() {
+        let (al, ar) = match stopped {
+            true => (0., 0.),
+            false => deck_a.next(&music.songs, &mut music.queue),
+        };
+        let (bl, br) = match stopped {
+            true => (0., 0.),
+            false => deck_b.next(&music.songs, &mut music.queue),
+        };
+        let (ga, gb) = (xfade_a.next(coef), xfade_b.next(coef));
+        let (sl, sr) = (ga * al + gb * bl, ga * ar + gb * br);
+
+        let song_volume = music.song_gain.next(coef) * music.duck_gains[i];
+        left[i] += song_volume * sl;
+        right[i] += song_volume * sr;
+        music.song_meter.add(sl, sr);
+        if let Some(tap) = &mut music.record_tap {
+            tap.song(sl, sr);
+        }
+    }
+
+    // Publish where the decks got to
+    for (deck, atomics) in music.decks.iter_mut().zip(music.shared.decks.iter()) {
+        deck.end(&music.songs, atomics);
+    }
+    music.song_meter.publish(&music.shared.song_strip.meter);
+
+    // Mix in the carts
+    if !stopped {
+        music.carts.process(left, right, &music.shared.carts, coef);
+    }
+
+    // Apply master volume to the sum
+    music.master_gain.set(f32::from_bits(atom!(master_volume)));
+    for i in 0..left.len() {
+        let master_volume = music.master_gain.next(coef);
+        left[i] *= master_volume;
+        right[i] *= master_volume;
+    }
+
+    // Keep the output under the limiter ceiling
+    let limiter = &music.shared.limiter;
+    let reduction = music.limiter.process(
+        left,
+        right,
+        f32::from_bits(limiter.ceiling.load(Relaxed)),
+        f32::from_bits(limiter.release.load(Relaxed)),
+        limiter.bypass.load(Relaxed),
+    );
+    limiter.reduction.store(reduction.to_bits(), Relaxed);
+
+    // Meter what goes out
+    let [left_meter, right_meter] = &mut music.output_meters;
+    for i in 0..left.len() {
+        left_meter.add(left[i], left[i]);
+        right_meter.add(right[i], right[i]);
+    }
+    left_meter.publish(&music.shared.output_meters[0]);
+    right_meter.publish(&music.shared.output_meters[1]);
+    music.loudness.process(left, right, &music.shared.loudness);
+
+    if let Some(tap) = &mut music.record_tap {
+        tap.output(left, right);
+    }
+}
+
+/// Plays a block of the cue player into the cue output. Its stereo is summed to mono, and it goes
+/// nowhere else, so it never reaches the output, meters or recorder.
+fn cue_callback(cue: &mut [f32], music: &mut MusicThread) {
+    let atomics = &music.shared.decks[CUE];
+    music.cue.begin(&music.songs, atomics, &music.queue);
+
+    // Silent like the decks while jack runs at another rate than the songs
+    let shared = &music.shared;
+    if shared.jack_rate.load(Relaxed) != shared.sample_rate.load(Relaxed) {
+        cue.fill(0.);
+        music.cue.end(&music.songs, atomics);
+        return;
+    }
+
+    let coef = music.smoothing;
+    let volume = f32::from_bits(music.shared.cue_volume.load(Relaxed));
+    music.cue_gain.set(volume);
+    for sample in cue.iter_mut() {
+        let (l, r) = music.cue.next(&music.songs, &mut music.queue);
+        *sample = music.cue_gain.next(coef) * 0.5 * (l + r);
+    }
+
+    music.cue.end(&music.songs, atomics);
+}
+
+/// Returns the gain of a channel strip, taking mute, solo and polarity into account
+fn strip_gain(strip: &StripAtomics, any_solo: bool) -> f32 {
+    let muted = strip.mute.load(Relaxed) || (any_solo && !strip.solo.load(Relaxed));
+    let volume = f32::from_bits(strip.volume.load(Relaxed));
+
+    if muted {
+        0.
+    } else if strip.invert.load(Relaxed) {
+        -volume
+    } else {
+        volume
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::limiter::Limiter;
+    use super::super::offline::testing::*;
+    use super::*;
+
+    #[test]
+    fn long_blocks_are_mixed_in_pieces() {
+        let config = config();
+        let (mut mixer, _ac_send, shared, _old_queues) =
+            Mixer::new(Vec::new(), Vec::new(), RATE, &config);
+        shared.inputs[0].volume.store(1f32.to_bits(), Relaxed);
+
+        let frames = 3 * MAX_BLOCK + 100;
+        let mic: Vec<f32> = (0..frames).map(|i| i as f32 / frames as f32).collect();
+        let line = vec![0.; frames];
+        let (mut left, mut right, mut cue) = (vec![0.; frames], vec![0.; frames], vec![0.; frames]);
+        let inputs = [&mic[..], &line[..]];
+        mixer.process(&mut left, &mut right, &mut cue, inputs.iter().copied());
+
+        // Nothing grew, and the mic comes through the seams between the pieces unchanged
+        assert_eq!(mixer.music.duck_gains.capacity(), MAX_BLOCK);
+        let latency = Limiter::latency(RATE);
+        for (i, &s) in left[latency..].iter().enumerate() {
+            assert!(
+                (s - mic[i]).abs() < 1e-6,
+                "{} at {}, expected {}",
+                s,
+                i,
+                mic[i]
+            );
+        }
+    }
+}
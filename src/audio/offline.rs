@@ -0,0 +1,523 @@
+//! Offline rendering. Runs the mixing engine without jack, feeding it wav files as the inputs of
+//! the configured channel strips and applying a script of controls at given times, then writes
+//! the stereo mix to a wav file. Useful for reproducing a show's mix after the fact.
+//!
+//! Script format, one command per line. Times are in seconds or mm:ss, blank lines and lines
+//! starting with # are ignored. Input strips are referred to by their port name, and carts by
+//! their name. Volumes are in dB, or "off". Transport commands (load, play, pause, jump, fade,
+//! loop, tempo, key) apply to deck A, or to the deck picked by the latest deck command. tempo is
+//! in percent off the song's own, and key shifts the pitch in semitones. loop in and loop out
+//! take the loop points as song times, and loop out starts looping. loop exit leaves the loop at
+//! its end, and loop off leaves it right away. The crossfader goes from 0 (deck A) to 1 (deck B).
+//! Songs added with queue follow on as songs end, depending on the after command (stop, next or
+//! loop), starting the given overlap in seconds early:
+//!
+//! ```text
+//! 0:00    volume mic_in 0
+//! 0:00    volume music -2
+//! 0:00    volume master -3
+//! 0:00    load Some Song.mp3
+//! 0:05    play
+//! 0:20    cart Station ID
+//! 0:40    mute mic_in on
+//! 0:42    mute mic_in off
+//! 1:30    pause
+//! 1:31    jump 2:00
+//! 1:35    play
+//! 1:40    loop in 2:10
+//! 1:40    loop out 2:18
+//! 2:30    loop exit
+//! 3:00    tempo +4
+//! 3:00    key -1
+//! 4:00    deck b
+//! 4:00    load Next Song.mp3
+//! 4:40    play
+//! 4:40    curve power
+//! 4:40    crossfader 0.5
+//! 4:50    crossfader 1
+//! 4:50    deck a
+//! 4:50    fade 10
+//! 4:50    after next
+//! 4:50    overlap 5
+//! 4:50    queue Last Song.mp3
+//! 9:00    end
+//! ```
+
+use super::resample::resample;
+use super::{
+    db_to_gain, load_carts, load_songs, parse_time, AudioControl, CrossfadeCurve, DeckControl,
+    EndMode, Mixer, SharedAtomics, Strip,
+};
+use crate::config::Config;
+
+use imgui::ImString;
+use std::fs;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::Sender;
+
+/// Sample rate of the rendered output when none is given. Inputs and songs are resampled to the
+/// output rate.
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+/// Number of samples mixed at once, similar to a jack period
+const BLOCK_SIZE: usize = 1024;
+
+/// A scripted action and the sample it happens at
+struct Event {
+    at: usize,
+    action: Action,
+}
+
+enum Action {
+    Control(AudioControl),
+    Volume(Strip, f32),
+    MasterVolume(f32),
+    Crossfader(f32),
+    Tempo(usize, f32),
+    Pitch(usize, f32),
+    Curve(CrossfadeCurve),
+    Queue(usize),
+    After(EndMode),
+    Overlap(usize),
+    Mute(Strip, bool),
+    Solo(Strip, bool),
+    Invert(Strip, bool),
+    End,
+}
+
+/// Entry point of the render mode. Takes the command line arguments following "render":
+/// `<script> <output.wav> [--input <port>=<file.wav>]... [--rate <hz>]`
+pub fn render(args: &[String]) {
+    let usage = "usage: james_mixer render <script> <output.wav> \
+                 [--input <port>=<file.wav>]... [--rate <hz>]";
+
+    let config = Config::load();
+
+    // Parse arguments
+    let (script_path, out_path) = match args {
+        [script, out, ..] => (script, out),
+        _ => panic!("{}", usage),
+    };
+
+    // Wav file of each input strip, if given
+    let mut input_paths = vec![None; config.inputs.len()];
+    let mut sample_rate = DEFAULT_SAMPLE_RATE;
+    let mut rest = args[2..].iter();
+    while let Some(flag) = rest.next() {
+        let value = rest.next().expect(usage);
+        match flag.as_str() {
+            "--input" => {
+                let (port, path) = value.split_once('=').expect(usage);
+                let i = input_index(&config, port).expect("No input strip with that port");
+                input_paths[i] = Some(path);
+            }
+            "--rate" => sample_rate = value.parse().expect(usage),
+            _ => panic!("{}", usage),
+        }
+    }
+
+    let mut inputs: Vec<Vec<f32>> = input_paths
+        .into_iter()
+        .map(|path| path.map_or(Vec::new(), |p| read_wav(p, sample_rate)))
+        .collect();
+
+    // Load songs and script
+    let (song_index_map, songs) = load_songs(sample_rate, &config.normalize);
+    let carts = load_carts(sample_rate, &config.carts);
+    let script = fs::read_to_string(script_path).expect("Could not read script");
+    let mut events = parse_script(&script, sample_rate, &config, |name| {
+        match song_index_map.get(&ImString::new(name)) {
+            Some(&i) => i,
+            None => panic!("Song {} not found in ./music", name),
+        }
+    });
+
+    // Events are applied in time order, keeping script order for events at the same time
+    events.sort_by_key(|e| e.at);
+
+    // Render until the end command, or until the inputs run out
+    let end = match events.iter().find(|e| matches!(e.action, Action::End)) {
+        Some(e) => e.at,
+        None => inputs.iter().map(Vec::len).max().unwrap_or(0),
+    };
+    assert!(
+        end > 0,
+        "Nothing to render: no end command and no inputs given"
+    );
+
+    // Silence-pad inputs up to the end of the render
+    for input in &mut inputs {
+        input.resize(end.max(input.len()), 0.);
+    }
+
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(out_path, spec).expect("Could not create output");
+
+    let (mixer, ac_send, shared, _old_queues) = Mixer::new(songs, carts, sample_rate, &config);
+    mix(
+        mixer,
+        &ac_send,
+        &shared,
+        events,
+        &inputs,
+        end,
+        |left, right| {
+            for (&l, &r) in left.iter().zip(right) {
+                writer.write_sample(l).unwrap();
+                writer.write_sample(r).unwrap();
+            }
+        },
+    );
+
+    writer.finalize().unwrap();
+    println!("Rendered {} samples to {}", end, out_path);
+}
+
+/// Runs the mixer up to end, applying the events, sorted by time, on the exact sample they are
+/// due. inputs holds at least end samples of each input strip. Each block of the left and right
+/// output is passed to output in turn.
+fn mix(
+    mut mixer: Mixer,
+    ac_send: &Sender<AudioControl>,
+    shared: &SharedAtomics,
+    events: Vec<Event>,
+    inputs: &[Vec<f32>],
+    end: usize,
+    mut output: impl FnMut(&[f32], &[f32]),
+) {
+    let mut left = vec![0.; BLOCK_SIZE];
+    let mut right = vec![0.; BLOCK_SIZE];
+
+    // Nobody listens to the cue bus of a render
+    let mut cue = vec![0.; BLOCK_SIZE];
+    let mut events = events.into_iter().peekable();
+    let mut pos = 0;
+
+    // Copy of the play queue, and the number of songs taken off it when it was last sent
+    let mut queue = Vec::new();
+    let mut seen = 0;
+
+    while pos < end {
+        // Apply every event that is due
+        while let Some(e) = events.next_if(|e| e.at <= pos) {
+            match e.action {
+                Action::Control(c) => ac_send.send(c).unwrap(),
+                Action::Volume(s, v) => shared.strip(s).volume.store(v.to_bits(), Relaxed),
+                Action::MasterVolume(v) => shared.master_volume.store(v.to_bits(), Relaxed),
+                Action::Crossfader(x) => shared.crossfader.position.store(x.to_bits(), Relaxed),
+                Action::Tempo(deck, x) => {
+                    let tempo = 1. + x / 100.;
+                    shared.decks[deck].tempo.store(tempo.to_bits(), Relaxed);
+                }
+                Action::Pitch(deck, x) => shared.decks[deck].pitch.store(x.to_bits(), Relaxed),
+                Action::Curve(c) => shared.crossfader.set_curve(c),
+                Action::Queue(song) => {
+                    let advanced = shared.queue.advanced.load(Relaxed);
+                    queue.drain(..(advanced - seen).min(queue.len()));
+                    seen = advanced;
+                    queue.push(song);
+                    let c = AudioControl::Queue(queue.clone(), seen);
+                    ac_send.send(c).unwrap();
+                }
+                Action::After(mode) => shared.queue.set_end_mode(mode),
+                Action::Overlap(n) => shared.queue.overlap.store(n, Relaxed),
+                Action::Mute(s, on) => shared.strip(s).mute.store(on, Relaxed),
+                Action::Solo(s, on) => shared.strip(s).solo.store(on, Relaxed),
+                Action::Invert(s, on) => shared.strip(s).invert.store(on, Relaxed),
+                Action::End => (),
+            }
+        }
+
+        // Mix up to the next event so that events land on the exact sample
+        let next = events.peek().map_or(end, |e| e.at).min(end);
+        let len = (next - pos).min(BLOCK_SIZE);
+        let (l, r) = (&mut left[..len], &mut right[..len]);
+        mixer.process(
+            l,
+            r,
+            &mut cue[..len],
+            inputs.iter().map(|input| &input[pos..pos + len]),
+        );
+
+        output(l, r);
+
+        pos += len;
+    }
+}
+
+/// Reads a wav file into mono f32 samples at sample_rate, averaging channels together.
+fn read_wav(path: &str, sample_rate: u32) -> Vec<f32> {
+    let mut reader = hound::WavReader::open(path).expect("Could not open input wav");
+    let spec = reader.spec();
+
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().map(Result::unwrap).collect(),
+        hound::SampleFormat::Int => {
+            let max = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.unwrap() as f32 / max)
+                .collect()
+        }
+    };
+
+    let channels = spec.channels as usize;
+    let mono: Vec<f32> = samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+
+    resample(&mono, 1, spec.sample_rate, sample_rate)
+}
+
+/// Parses a render script into events at sample_rate. song_index looks up a song name in the
+/// song library.
+fn parse_script(
+    script: &str,
+    sample_rate: u32,
+    config: &Config,
+    song_index: impl Fn(&str) -> usize,
+) -> Vec<Event> {
+    let to_samples = |seconds: f64| (seconds * sample_rate as f64).round() as usize;
+
+    let mut events = Vec::new();
+
+    // Deck the transport commands go to
+    let mut deck = 0;
+
+    for (n, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // Panics with the offending line number
+        let bad = |what: &str| -> ! { panic!("Script line {}: {}: {}", n + 1, what, line) };
+
+        // Split into time, command and the rest of the line as the argument
+        let (time, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let (command, arg) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let arg = arg.trim();
+
+        let at = match parse_time(time) {
+            Some(t) => to_samples(t),
+            None => bad("invalid time"),
+        };
+
+        let transport = |c| Action::Control(AudioControl::Deck(deck, c));
+
+        let action = match command {
+            "deck" => {
+                deck = match arg {
+                    "a" | "A" => 0,
+                    "b" | "B" => 1,
+                    _ => bad("expected deck a or b"),
+                };
+                continue;
+            }
+            "play" => transport(DeckControl::Paused(false)),
+            "pause" => transport(DeckControl::Paused(true)),
+            "load" => transport(DeckControl::Load(song_index(arg))),
+            "jump" => match parse_time(arg) {
+                Some(t) => transport(DeckControl::JumpTo(to_samples(t))),
+                None => bad("invalid jump time"),
+            },
+            "fade" => match parse_time(arg) {
+                Some(t) => transport(DeckControl::FadeOut(to_samples(t))),
+                None => bad("invalid fade time"),
+            },
+            "loop" => {
+                let (what, time) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+                let point = || match parse_time(time.trim()) {
+                    Some(t) => to_samples(t),
+                    None => bad("invalid loop time"),
+                };
+                transport(match what {
+                    "in" => DeckControl::LoopStart(point()),
+                    "out" => DeckControl::LoopEnd(point()),
+                    "on" => DeckControl::Looping(true),
+                    "off" => DeckControl::Looping(false),
+                    "exit" => DeckControl::ExitLoop,
+                    _ => bad("expected loop in, out, on, off or exit"),
+                })
+            }
+            "queue" => Action::Queue(song_index(arg)),
+            "after" => match EndMode::from_name(arg) {
+                Some(mode) => Action::After(mode),
+                None => bad("expected stop, next or loop"),
+            },
+            "overlap" => match parse_time(arg) {
+                Some(t) => Action::Overlap(to_samples(t)),
+                None => bad("invalid overlap time"),
+            },
+            "crossfader" => match arg.parse() {
+                Ok(x) if (0. ..=1.).contains(&x) => Action::Crossfader(x),
+                _ => bad("expected a crossfader position from 0 to 1"),
+            },
+            "tempo" => match arg.parse() {
+                Ok(x) if (-20. ..=20.).contains(&x) => Action::Tempo(deck, x),
+                _ => bad("expected a tempo from -20 to +20 percent"),
+            },
+            "key" => match arg.parse() {
+                Ok(x) if (-12. ..=12.).contains(&x) => Action::Pitch(deck, x),
+                _ => bad("expected a key shift from -12 to +12 semitones"),
+            },
+            "curve" => match CrossfadeCurve::from_name(arg) {
+                Some(curve) => Action::Curve(curve),
+                None => bad("expected curve linear, power or cut"),
+            },
+            "cart" => match config.carts.iter().position(|cart| cart.name == arg) {
+                Some(i) => Action::Control(AudioControl::Cart(i)),
+                None => bad("unknown cart"),
+            },
+            "end" => Action::End,
+
+            // Channel strip commands, which take a channel and a value
+            _ => {
+                let (channel, value) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+                let value = value.trim();
+
+                // Volumes are in dB like the ui faders, with off for silence
+                let volume = || match value {
+                    "off" => 0.,
+                    _ => match value.parse() {
+                        Ok(db) => db_to_gain(db),
+                        Err(_) => bad("invalid volume"),
+                    },
+                };
+
+                // The master fader is not a strip and only has a volume
+                let strip = match (channel, input_index(config, channel)) {
+                    ("master", _) => None,
+                    ("music", _) => Some(Strip::Music),
+                    (_, Some(i)) => Some(Strip::Input(i)),
+                    _ => bad("unknown channel"),
+                };
+
+                let switch = || match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => bad("expected on or off"),
+                };
+
+                match (command, strip) {
+                    ("volume", None) => Action::MasterVolume(volume()),
+                    ("volume", Some(strip)) => Action::Volume(strip, volume()),
+                    ("mute", Some(strip)) => Action::Mute(strip, switch()),
+                    ("solo", Some(strip)) => Action::Solo(strip, switch()),
+                    ("invert", Some(strip)) => Action::Invert(strip, switch()),
+                    _ => bad("unknown command"),
+                }
+            }
+        };
+
+        events.push(Event { at, action });
+    }
+
+    events
+}
+
+/// Returns the index of the input strip with the given port name
+fn input_index(config: &Config, port: &str) -> Option<usize> {
+    config.inputs.iter().position(|input| input.port == port)
+}
+
+/// Renders scripts for the tests of the engine, with made up songs and inputs
+#[cfg(test)]
+pub(super) mod testing {
+    use super::super::limiter::Limiter;
+    use super::super::song::{Song, SongLevels};
+    use super::super::Markers;
+    use super::*;
+
+    pub const RATE: u32 = 48000;
+
+    /// Returns the sample at a time in seconds
+    pub fn at(seconds: f64) -> usize {
+        (seconds * RATE as f64).round() as usize
+    }
+
+    /// Makes a song of the given length in seconds, both sides playing f of the frame
+    pub fn song(seconds: f64, f: impl Fn(usize) -> f32) -> Song {
+        let data: Vec<f32> = (0..at(seconds)).flat_map(|i| [f(i), f(i)]).collect();
+        Song {
+            data: data.into(),
+            channels: 2,
+            sample_rate: RATE,
+            levels: SongLevels {
+                loudness: 0.,
+                peak: 0.,
+                tagged: false,
+                gain: 0.,
+            },
+            markers: Markers::default(),
+        }
+    }
+
+    /// The default config without smoothing, transport fades or limiting, so the output is the
+    /// songs as they are
+    pub fn config() -> Config {
+        let mut config = Config {
+            smoothing: 0.,
+            transport_fade: 0.,
+            ..Config::default()
+        };
+        config.limiter.bypass = true;
+        config
+    }
+
+    /// Renders a script up to its end command, with songs named a, b and so on, and the given
+    /// samples of the first inputs. Returns the left output up to the end, without the limiter's
+    /// delay.
+    pub fn render(
+        script: &str,
+        songs: Vec<Song>,
+        config: &Config,
+        inputs: &[Vec<f32>],
+    ) -> Vec<f32> {
+        let names = ["a", "b", "c"];
+        let mut events = parse_script(script, RATE, config, |name| {
+            names.iter().position(|&n| n == name).unwrap()
+        });
+        events.sort_by_key(|e| e.at);
+        let latency = Limiter::latency(RATE);
+        let end = events
+            .iter()
+            .find(|e| matches!(e.action, Action::End))
+            .unwrap()
+            .at
+            + latency;
+
+        let mut inputs = inputs.to_vec();
+        inputs.resize(config.inputs.len(), Vec::new());
+        for input in &mut inputs {
+            input.resize(end, 0.);
+        }
+
+        let (mixer, ac_send, shared, _old_queues) = Mixer::new(songs, Vec::new(), RATE, config);
+        let mut left = Vec::new();
+        mix(mixer, &ac_send, &shared, events, &inputs, end, |l, _| {
+            left.extend_from_slice(l)
+        });
+        left.split_off(latency)
+    }
+
+    /// Asserts that every sample in a range of the output is close to a level
+    pub fn assert_level(output: &[f32], from: f64, to: f64, level: f32) {
+        for (i, &s) in output[at(from)..at(to)].iter().enumerate() {
+            assert!(
+                (s - level).abs() < 1e-4,
+                "{} at {:.4} s, expected {}",
+                s,
+                from + i as f64 / RATE as f64,
+                level
+            );
+        }
+    }
+}
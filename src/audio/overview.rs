@@ -0,0 +1,28 @@
+/// Number of points in a song's overview, spread evenly over the song
+pub const OVERVIEW_POINTS: usize = 1000;
+
+/// A coarse picture of a song's waveform: the lowest and highest sample of each stretch of the
+/// song, over both channels
+pub struct Overview {
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+}
+
+impl Overview {
+    /// Computes the overview of interleaved song data with the given number of channels
+    pub fn new(data: &[f32], channels: usize) -> Overview {
+        let frames = data.len() / channels;
+        let mut min = vec![0.; OVERVIEW_POINTS];
+        let mut max = vec![0.; OVERVIEW_POINTS];
+
+        for (i, frame) in data.chunks_exact(channels).enumerate() {
+            let point = i * OVERVIEW_POINTS / frames;
+            for &s in frame {
+                min[point] = s.min(min[point]);
+                max[point] = s.max(max[point]);
+            }
+        }
+
+        Overview { min, max }
+    }
+}
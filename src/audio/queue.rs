@@ -0,0 +1,99 @@
+use super::QueueAtomics;
+
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+/// What a deck does when its song ends
+#[derive(Clone, Copy, PartialEq)]
+pub enum EndMode {
+    /// Pause at the end of the song
+    Stop,
+
+    /// Go on with the next song of the queue, or stop if it is empty
+    Next,
+
+    /// Play the song again from the start
+    Loop,
+}
+
+impl EndMode {
+    /// Every mode, in ui order
+    pub const ALL: [EndMode; 3] = [EndMode::Stop, EndMode::Next, EndMode::Loop];
+
+    /// Returns the name of the mode, as used in the ui and scripts
+    pub fn name(self) -> &'static str {
+        match self {
+            EndMode::Stop => "stop",
+            EndMode::Next => "next",
+            EndMode::Loop => "loop",
+        }
+    }
+
+    /// Looks up a mode by its name
+    pub fn from_name(name: &str) -> Option<EndMode> {
+        EndMode::ALL
+            .iter()
+            .copied()
+            .find(|mode| mode.name() == name)
+    }
+}
+
+/// The play queue: songs waiting to play, which the decks take from as their songs end. The ui
+/// keeps its own copy and sends the whole queue on every edit. Songs the engine took meanwhile
+/// are counted, so an edit made before the ui noticed does not bring them back. Replaced queues
+/// are sent back to be freed off the audio thread.
+pub struct PlayQueue {
+    // Songs waiting, next first
+    songs: VecDeque<usize>,
+
+    // Number of songs taken off the queue so far
+    advanced: usize,
+
+    atomics: Arc<QueueAtomics>,
+
+    // Where replaced queues go to be freed
+    old_queues: Sender<VecDeque<usize>>,
+}
+
+impl PlayQueue {
+    /// Creates an empty queue
+    pub fn new(atomics: Arc<QueueAtomics>, old_queues: Sender<VecDeque<usize>>) -> PlayQueue {
+        PlayQueue {
+            songs: VecDeque::new(),
+            advanced: 0,
+            atomics,
+            old_queues,
+        }
+    }
+
+    /// Replaces the queue with the ui's copy, which it made after seen songs had been taken. The
+    /// copy's buffer is taken over as it is, so nothing is allocated.
+    pub fn set(&mut self, songs: Vec<usize>, seen: usize) {
+        let mut songs = VecDeque::from(songs);
+        let taken = self.advanced.saturating_sub(seen).min(songs.len());
+        songs.drain(..taken);
+        let old = mem::replace(&mut self.songs, songs);
+        let _ = self.old_queues.send(old);
+    }
+
+    /// Takes the next song off the queue
+    pub fn pop(&mut self) -> Option<usize> {
+        let song = self.songs.pop_front()?;
+        self.advanced += 1;
+        self.atomics.advanced.store(self.advanced, Relaxed);
+        Some(song)
+    }
+
+    /// Returns what decks do when their song ends
+    pub fn end_mode(&self) -> EndMode {
+        EndMode::ALL[self.atomics.end_mode.load(Relaxed)]
+    }
+
+    /// Returns how many frames a song that follows another starts before the other ends
+    pub fn overlap(&self) -> usize {
+        self.atomics.overlap.load(Relaxed)
+    }
+}
@@ -0,0 +1,629 @@
+use super::flac::{FlacWriter, MAX_CHANNELS};
+use super::limiter::Limiter;
+use crate::config::{Config, RecordFormat, RecordTracks};
+
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::*};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Seconds of audio the ring buffers hold, so the writer thread can fall that far behind
+const BUFFER_SECONDS: usize = 10;
+
+/// Time between writes to the files
+const WRITE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Frames moved from the ring buffers to the files at a time
+const CHUNK_FRAMES: usize = 4096;
+
+/// Bits per sample of recordings
+const BITS: u32 = 24;
+
+/// Bytes of samples a WAV file is split after. Its header counts bytes in 32 bits, so this leaves
+/// room for the header below 4 GiB.
+const MAX_WAV_BYTES: u64 = u32::MAX as u64 - (1 << 20);
+
+/// Channels recorded: the output's left and right, then the song's left and right before its
+/// fader, then each input before its fader. Only the output is recorded when mixing down.
+const OUTPUT_CHANNEL: usize = 0;
+const SONG_CHANNEL: usize = 2;
+const INPUT_CHANNEL: usize = 4;
+
+/// Records the output, and optionally every source, to disk. The audio thread pushes each
+/// channel into its own ring buffer through a RecordTap, and a writer thread encodes them to
+/// files, so recording carries on no matter what the ui thread is doing.
+pub struct Recorder {
+    commands: Sender<Command>,
+    atomics: Arc<RecorderAtomics>,
+    directory: PathBuf,
+    format: RecordFormat,
+
+    // File name suffix of each file a recording is made of, empty for a single file
+    suffixes: Vec<String>,
+
+    // Why recordings are not made as configured, if they are not
+    notice: Option<String>,
+
+    // Time in the name of the last recording, and the number added to it. A recording started
+    // within the same second gets the next number, so it does not take the earlier one's files.
+    last_name: Cell<(String, usize)>,
+
+    // Joined when dropped, so the files are finished before the program exits
+    writer: Option<JoinHandle<()>>,
+}
+
+/// The audio thread's end of the recorder
+pub struct RecordTap {
+    channels: Vec<HeapProducer<f32>>,
+    atomics: Arc<RecorderAtomics>,
+
+    // Whether the current block is recorded
+    recording: bool,
+}
+
+struct RecorderAtomics {
+    // Set by the writer thread while the files are open, to have the audio thread push audio
+    record: AtomicBool,
+
+    // Set by the audio thread while it pushes audio
+    active: AtomicBool,
+
+    // Frames and bytes written to the current files
+    frames: AtomicU64,
+    bytes: AtomicU64,
+
+    // Frames lost because the ring buffers were full
+    dropped: AtomicU64,
+}
+
+enum Command {
+    Start(Vec<PathBuf>),
+    Stop,
+    Quit,
+}
+
+/// Status of a running recording
+pub struct RecordingStatus {
+    pub seconds: f64,
+    pub bytes: u64,
+    pub dropped: u64,
+}
+
+impl Recorder {
+    /// Starts the writer thread, idle until a recording is started
+    pub fn new(sample_rate: u32, config: &Config) -> (Recorder, RecordTap) {
+        let inputs = config.inputs.len();
+        let tracks = config.recorder.tracks;
+
+        let channels = match tracks {
+            RecordTracks::Mix => 2,
+            _ => INPUT_CHANNEL + inputs,
+        };
+
+        // Channels of each file, and what is added to its name
+        let (layout, suffixes): (Vec<Range<usize>>, Vec<String>) = match tracks {
+            RecordTracks::Mix | RecordTracks::Multichannel => {
+                let all = 0..channels;
+                (vec![all], vec![String::new()])
+            }
+            RecordTracks::Separate => {
+                let mut files = vec![
+                    (OUTPUT_CHANNEL..OUTPUT_CHANNEL + 2, "_output".to_owned()),
+                    (SONG_CHANNEL..SONG_CHANNEL + 2, "_song".to_owned()),
+                ];
+                files.extend(config.inputs.iter().enumerate().map(|(i, input)| {
+                    let channel = INPUT_CHANNEL + i;
+                    (
+                        channel..channel + 1,
+                        format!("_{}", file_name_part(&input.name)),
+                    )
+                }));
+                files.into_iter().unzip()
+            }
+        };
+
+        let (producers, consumers) = (0..channels)
+            .map(|_| HeapRb::new(BUFFER_SECONDS * sample_rate as usize).split())
+            .unzip();
+        let (commands, command_recv) = channel();
+
+        let atomics = Arc::new(RecorderAtomics {
+            record: AtomicBool::new(false),
+            active: AtomicBool::new(false),
+            frames: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+
+        // The output comes out of the limiter later than the sources went in. Its first frames
+        // are left out so the tracks line up.
+        let output_delay = match tracks {
+            RecordTracks::Mix => 0,
+            _ => Limiter::latency(sample_rate),
+        };
+
+        // FLAC holds too few channels for a multichannel recording of many inputs, which is
+        // made as WAV instead
+        let (format, notice) = match config.recorder.format {
+            RecordFormat::Flac if layout.iter().any(|file| file.len() > MAX_CHANNELS) => {
+                let notice = format!(
+                    "FLAC holds at most {} channels, recording {} channels as WAV",
+                    MAX_CHANNELS, channels
+                );
+                println!("{}", notice);
+                (RecordFormat::Wav, Some(notice))
+            }
+            format => (format, None),
+        };
+
+        let writer_atomics = atomics.clone();
+        let writer = thread::spawn(move || {
+            writer_thread(
+                command_recv,
+                consumers,
+                layout,
+                output_delay,
+                writer_atomics,
+                sample_rate,
+                format,
+            )
+        });
+
+        let recorder = Recorder {
+            commands,
+            atomics: atomics.clone(),
+            directory: PathBuf::from(&config.recorder.directory),
+            format,
+            suffixes,
+            notice,
+            last_name: Cell::default(),
+            writer: Some(writer),
+        };
+        let tap = RecordTap {
+            channels: producers,
+            atomics,
+            recording: false,
+        };
+        (recorder, tap)
+    }
+
+    /// Starts recording to new files named after the current time, stopping any recording
+    /// first. A number is added to the name if it is taken. Returns the path of the first file,
+    /// the output's.
+    pub fn start(&self) -> PathBuf {
+        let extension = match self.format {
+            RecordFormat::Wav => "wav",
+            RecordFormat::Flac => "flac",
+        };
+        let time = chrono::Local::now()
+            .format("recording_%Y-%m-%d_%H-%M-%S")
+            .to_string();
+        let paths_for = |number: usize| -> Vec<PathBuf> {
+            let name = match number {
+                1 => time.clone(),
+                _ => format!("{}_{}", time, number),
+            };
+            self.suffixes
+                .iter()
+                .map(|suffix| {
+                    self.directory
+                        .join(format!("{}{}.{}", name, suffix, extension))
+                })
+                .collect()
+        };
+
+        // The files of the last recording may not have been created yet, so its number is
+        // skipped even if they are not on disk
+        let (last_time, last_number) = self.last_name.take();
+        let mut number = if last_time == time {
+            last_number + 1
+        } else {
+            1
+        };
+        while paths_for(number).iter().any(|path| path.exists()) {
+            number += 1;
+        }
+        let paths = paths_for(number);
+        self.last_name.set((time, number));
+
+        let first = paths[0].clone();
+        self.commands.send(Command::Stop).unwrap();
+        self.commands.send(Command::Start(paths)).unwrap();
+        first
+    }
+
+    /// Stops recording and finishes the files
+    pub fn stop(&self) {
+        self.commands.send(Command::Stop).unwrap();
+    }
+
+    /// Returns why recordings are not made as configured, or None if they are
+    pub fn notice(&self) -> Option<&str> {
+        self.notice.as_deref()
+    }
+
+    /// Returns the status of the recording, or None if not recording
+    pub fn status(&self, sample_rate: u32) -> Option<RecordingStatus> {
+        if !self.atomics.record.load(Relaxed) {
+            return None;
+        }
+
+        Some(RecordingStatus {
+            seconds: self.atomics.frames.load(Relaxed) as f64 / sample_rate as f64,
+            bytes: self.atomics.bytes.load(Relaxed),
+            dropped: self.atomics.dropped.load(Relaxed),
+        })
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Quit);
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl RecordTap {
+    /// Starts a block of the given number of frames. It is recorded if recording and every ring
+    /// buffer has room for all of it, so the channels stay aligned.
+    pub fn begin(&mut self, frames: usize) {
+        // Release so that once the writer thread sees active go false, it also sees every
+        // sample pushed before
+        let record = self.atomics.record.load(Acquire);
+        self.atomics.active.store(record, Release);
+
+        self.recording = record && self.channels.iter().all(|c| c.free_len() >= frames);
+        if record && !self.recording {
+            self.atomics.dropped.fetch_add(frames as u64, Relaxed);
+        }
+    }
+
+    /// Records a block of an input, before its fader
+    pub fn input(&mut self, input: usize, samples: &[f32]) {
+        self.push(INPUT_CHANNEL + input, samples);
+    }
+
+    /// Records one frame of the song, before its fader
+    pub fn song(&mut self, left: f32, right: f32) {
+        self.push(SONG_CHANNEL, &[left]);
+        self.push(SONG_CHANNEL + 1, &[right]);
+    }
+
+    /// Records a block of the output
+    pub fn output(&mut self, left: &[f32], right: &[f32]) {
+        self.push(OUTPUT_CHANNEL, left);
+        self.push(OUTPUT_CHANNEL + 1, right);
+    }
+
+    /// Pushes samples to a channel's ring buffer, unless the block is not recorded or the
+    /// channel is not
+    fn push(&mut self, channel: usize, samples: &[f32]) {
+        if let (true, Some(producer)) = (self.recording, self.channels.get_mut(channel)) {
+            producer.push_slice(samples);
+        }
+    }
+}
+
+/// Makes a name safe to use in a file name
+fn file_name_part(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// A recording file being written, in parts if it grows too big for its format
+struct RecordFile {
+    // Path of the first part, and the number of the part being written, from 1
+    path: PathBuf,
+    part: usize,
+
+    out: Output,
+}
+
+impl RecordFile {
+    /// Returns the path of the part being written. Parts after the first are numbered.
+    fn part_path(&self) -> PathBuf {
+        if self.part == 1 {
+            return self.path.clone();
+        }
+
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = self.path.extension().unwrap_or_default().to_string_lossy();
+        self.path
+            .with_file_name(format!("{}_part{}.{}", stem, self.part, extension))
+    }
+
+    /// Writes interleaved samples, going on in a new part first if they do not fit in this
+    /// one. Returns the size in bytes of the part finished, if one was.
+    fn write(
+        &mut self,
+        samples: &[f32],
+        format: RecordFormat,
+        channels: usize,
+        sample_rate: u32,
+    ) -> io::Result<Option<u64>> {
+        let mut finished = None;
+        if self.out.is_full(samples.len()) {
+            let full_path = self.part_path();
+            self.part += 1;
+            let next = Output::create(&self.part_path(), format, channels, sample_rate)?;
+            mem::replace(&mut self.out, next).finalize()?;
+            println!("Recorded to {}", full_path.display());
+            println!("Recording to {}", self.part_path().display());
+            finished = Some(fs::metadata(&full_path)?.len());
+        }
+
+        self.out.write(samples)?;
+        Ok(finished)
+    }
+}
+
+/// An encoder writing a recording file
+enum Output {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Flac(FlacWriter<BufWriter<File>>),
+}
+
+impl Output {
+    /// Creates a file to record to. An existing file is not overwritten.
+    fn create(
+        path: &Path,
+        format: RecordFormat,
+        channels: usize,
+        sample_rate: u32,
+    ) -> io::Result<Output> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        Ok(match format {
+            RecordFormat::Wav => {
+                let spec = hound::WavSpec {
+                    channels: channels as u16,
+                    sample_rate,
+                    bits_per_sample: BITS as u16,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                let file = BufWriter::new(File::create_new(path)?);
+                Output::Wav(hound::WavWriter::new(file, spec).map_err(wav_error)?)
+            }
+            RecordFormat::Flac => {
+                let file = BufWriter::new(File::create_new(path)?);
+                match FlacWriter::new(file, channels, sample_rate, BITS) {
+                    Ok(writer) => Output::Flac(writer),
+                    Err(e) => {
+                        let _ = fs::remove_file(path);
+                        return Err(e);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Writes interleaved samples, converted to integers
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let max = ((1 << (BITS - 1)) - 1) as f32;
+        let to_int = |s: f32| (s.clamp(-1., 1.) * max) as i32;
+
+        match self {
+            Output::Wav(writer) => {
+                for &s in samples {
+                    writer.write_sample(to_int(s)).map_err(wav_error)?;
+                }
+                Ok(())
+            }
+            Output::Flac(writer) => {
+                let ints: Vec<i32> = samples.iter().map(|&s| to_int(s)).collect();
+                writer.write_samples(&ints)
+            }
+        }
+    }
+
+    /// Returns whether a WAV file is too full to take more samples. FLAC files have no limit.
+    fn is_full(&self, samples: usize) -> bool {
+        match self {
+            Output::Wav(writer) => {
+                (writer.len() as u64 + samples as u64) * (BITS / 8) as u64 > MAX_WAV_BYTES
+            }
+            Output::Flac(_) => false,
+        }
+    }
+
+    fn finalize(self) -> io::Result<()> {
+        match self {
+            Output::Wav(writer) => writer.finalize().map_err(wav_error),
+            Output::Flac(writer) => writer.finalize().map(|_| ()),
+        }
+    }
+}
+
+fn wav_error(e: hound::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Waits for commands, and writes whatever is in the ring buffers to the open files. layout
+/// holds the channels of each file, and output_delay the frames of the output to leave out at
+/// the start of a recording.
+fn writer_thread(
+    commands: Receiver<Command>,
+    mut consumers: Vec<HeapConsumer<f32>>,
+    layout: Vec<Range<usize>>,
+    output_delay: usize,
+    atomics: Arc<RecorderAtomics>,
+    sample_rate: u32,
+    format: RecordFormat,
+) {
+    let mut outputs: Vec<RecordFile> = Vec::new();
+    let mut buffers = vec![vec![0.; CHUNK_FRAMES]; consumers.len()];
+    let mut interleaved = Vec::with_capacity(CHUNK_FRAMES * consumers.len());
+
+    // Frames of the output still to leave out
+    let mut output_skip = 0;
+
+    // Bytes in the parts of the files already finished
+    let mut finished_bytes = 0;
+
+    loop {
+        let command = commands.recv_timeout(WRITE_INTERVAL);
+
+        // Stop the audio thread pushing, and wait until it is done with its last block before
+        // writing the rest out. Gives up after a second in case the audio thread is not running.
+        if let Ok(Command::Stop | Command::Quit) | Err(RecvTimeoutError::Disconnected) = command {
+            atomics.record.store(false, Release);
+            let start = Instant::now();
+            while atomics.active.load(Acquire) && start.elapsed() < Duration::from_secs(1) {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+
+        // Write out what has been recorded. Only frames every channel has are taken, as the
+        // audio thread may be partway through pushing a block.
+        if !outputs.is_empty() {
+            let output = &mut consumers[OUTPUT_CHANNEL..OUTPUT_CHANNEL + 2];
+            let skip = output.iter().map(|c| c.len()).min().unwrap_or(0);
+            let skip = skip.min(output_skip);
+            output.iter_mut().for_each(|c| {
+                c.skip(skip);
+            });
+            output_skip -= skip;
+
+            let mut result = Ok(());
+            loop {
+                let frames = consumers.iter().map(|c| c.len()).min().unwrap_or(0);
+                let n = frames.min(CHUNK_FRAMES);
+                if n == 0 {
+                    break;
+                }
+
+                for (consumer, buffer) in consumers.iter_mut().zip(buffers.iter_mut()) {
+                    consumer.pop_slice(&mut buffer[..n]);
+                }
+
+                for (file, channels) in outputs.iter_mut().zip(layout.iter()) {
+                    interleaved.clear();
+                    for i in 0..n {
+                        interleaved.extend(buffers[channels.clone()].iter().map(|b| b[i]));
+                    }
+                    match file.write(&interleaved, format, channels.len(), sample_rate) {
+                        Ok(finished) => finished_bytes += finished.unwrap_or(0),
+                        Err(e) => result = result.and(Err(e)),
+                    }
+                }
+                atomics.frames.fetch_add(n as u64, Relaxed);
+            }
+
+            let bytes: u64 = outputs
+                .iter()
+                .filter_map(|file| fs::metadata(file.part_path()).ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            atomics.bytes.store(finished_bytes + bytes, Relaxed);
+
+            if let Err(e) = result {
+                println!("Recording failed: {}", e);
+                atomics.record.store(false, Release);
+                outputs.clear();
+            }
+        }
+
+        match command {
+            Ok(Command::Start(paths)) => {
+                // Throw away anything left from an earlier recording
+                consumers.iter_mut().for_each(|c| {
+                    c.clear();
+                });
+
+                let created: io::Result<Vec<RecordFile>> = paths
+                    .into_iter()
+                    .zip(layout.iter())
+                    .map(|(path, channels)| {
+                        let out = Output::create(&path, format, channels.len(), sample_rate)
+                            .map_err(|e| {
+                                io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+                            })?;
+                        Ok(RecordFile { path, part: 1, out })
+                    })
+                    .collect();
+
+                match created {
+                    Ok(created) => {
+                        atomics.frames.store(0, Relaxed);
+                        atomics.bytes.store(0, Relaxed);
+                        atomics.dropped.store(0, Relaxed);
+                        atomics.record.store(true, Release);
+                        output_skip = output_delay;
+                        finished_bytes = 0;
+                        for file in &created {
+                            println!("Recording to {}", file.path.display());
+                        }
+                        outputs = created;
+                    }
+                    Err(e) => println!("Could not start recording: {}", e),
+                }
+            }
+
+            Ok(Command::Stop) => finish(&mut outputs),
+
+            Ok(Command::Quit) | Err(RecvTimeoutError::Disconnected) => {
+                finish(&mut outputs);
+                return;
+            }
+
+            Err(RecvTimeoutError::Timeout) => (),
+        }
+    }
+}
+
+/// Finishes the recording files
+fn finish(outputs: &mut Vec<RecordFile>) {
+    for file in outputs.drain(..) {
+        let path = file.part_path();
+        match file.out.finalize() {
+            Ok(()) => println!("Recorded to {}", path.display()),
+            Err(e) => println!("Could not finish {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RecorderConfig;
+
+    #[test]
+    fn restart_within_a_second_keeps_both_recordings() {
+        let directory = std::env::temp_dir().join(format!("recorder_test_{}", std::process::id()));
+        let config = Config {
+            recorder: RecorderConfig {
+                directory: directory.to_string_lossy().into_owned(),
+                format: RecordFormat::Wav,
+                tracks: RecordTracks::Mix,
+            },
+            ..Config::default()
+        };
+
+        let (recorder, _tap) = Recorder::new(48000, &config);
+        let first = recorder.start();
+        let second = recorder.start();
+        let third = recorder.start();
+        drop(recorder);
+
+        assert_ne!(first, second);
+        assert_ne!(second, third);
+        assert_ne!(first, third);
+        for path in [&first, &second, &third] {
+            assert!(path.exists(), "{} missing", path.display());
+        }
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
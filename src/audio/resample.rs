@@ -0,0 +1,61 @@
+use rubato::{FftFixedIn, Resampler};
+
+/// Number of input frames the resampler processes at once
+const CHUNK_SIZE: usize = 1024;
+
+/// Resamples interleaved audio data from one sample rate to another. Uses rubato's synchronous
+/// FFT resampler, which is transparent enough for broadcast. Done once at load time, never in
+/// the audio thread.
+pub fn resample(data: &[f32], channels: usize, from: u32, to: u32) -> Vec<f32> {
+    if from == to {
+        return data.to_vec();
+    }
+
+    // Rubato works on separate buffers for each channel
+    let frames = data.len() / channels;
+    let planar: Vec<Vec<f32>> = (0..channels)
+        .map(|c| data.iter().skip(c).step_by(channels).copied().collect())
+        .collect();
+
+    let mut resampler = FftFixedIn::<f32>::new(from as usize, to as usize, CHUNK_SIZE, 2, channels)
+        .expect("Could not create resampler");
+
+    // The resampler delays its output, so the first `delay` output frames are skipped and the
+    // end is flushed out with silence
+    let delay = resampler.output_delay();
+    let expected = (frames as u64 * to as u64 / from as u64) as usize;
+    let mut out: Vec<Vec<f32>> = vec![Vec::with_capacity(expected + delay + CHUNK_SIZE); channels];
+
+    let mut pos = 0;
+    while pos < frames {
+        let end = (pos + resampler.input_frames_next()).min(frames);
+        let chunk: Vec<&[f32]> = planar.iter().map(|ch| &ch[pos..end]).collect();
+
+        let resampled = if end - pos == resampler.input_frames_next() {
+            resampler.process(&chunk, None)
+        } else {
+            resampler.process_partial(Some(&chunk), None)
+        };
+
+        for (o, r) in out.iter_mut().zip(resampled.unwrap()) {
+            o.extend(r);
+        }
+
+        pos = end;
+    }
+
+    while out[0].len() < delay + expected {
+        let resampled = resampler.process_partial::<&[f32]>(None, None).unwrap();
+        for (o, r) in out.iter_mut().zip(resampled) {
+            o.extend(r);
+        }
+    }
+
+    // Interleave again
+    let mut result = Vec::with_capacity(expected * channels);
+    for i in delay..delay + expected {
+        result.extend(out.iter().map(|ch| ch[i]));
+    }
+
+    result
+}
@@ -0,0 +1,43 @@
+/// A parameter that glides towards its target value one sample at a time, so changes made once
+/// per block do not step and cause zipper noise.
+pub struct Smoothed {
+    current: f32,
+    target: f32,
+}
+
+impl Smoothed {
+    /// Creates a parameter resting at value
+    pub fn new(value: f32) -> Smoothed {
+        Smoothed {
+            current: value,
+            target: value,
+        }
+    }
+
+    /// Sets the value to glide towards
+    pub fn set(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    /// Advances one sample and returns the new value. coef comes from smoothing_coef.
+    pub fn next(&mut self, coef: f32) -> f32 {
+        self.current = self.target + coef * (self.current - self.target);
+
+        // Settle exactly on the target instead of approaching it forever
+        if (self.current - self.target).abs() < 1e-6 {
+            self.current = self.target;
+        }
+
+        self.current
+    }
+}
+
+/// Returns the per-sample coefficient of a one-pole smoother with a time constant of ms. A time
+/// of 0 turns smoothing off.
+pub fn smoothing_coef(ms: f32, sample_rate: u32) -> f32 {
+    if ms <= 0. {
+        0.
+    } else {
+        (-1000. / (ms * sample_rate as f32)).exp()
+    }
+}
@@ -1,9 +1,46 @@
+use super::loudness::LoudnessMeter;
+use super::markers::Markers;
+use super::resample::resample;
+use super::tags::LoudnessTags;
+use super::true_peak::{TruePeak, TP_DELAY};
+
 use minimp3::{Decoder, Error};
 use std::io::Read;
+use std::sync::Arc;
 
 /// Struct that holds PCM song data
 pub struct Song {
-    pub data: Vec<f32>,
+    /// Interleaved samples, `channels` samples per frame. Shared so the ui can build an overview
+    /// of the song while it is loaded in the mixer.
+    pub data: Arc<[f32]>,
+
+    /// Number of interleaved channels in data, 1 (mono) or 2 (stereo)
+    pub channels: usize,
+
+    /// Sample rate of data in Hz
+    pub sample_rate: u32,
+
+    /// Measured levels, and the gain applied when playing
+    pub levels: SongLevels,
+
+    /// Where the song starts and stops playing, and where its intro and outro are
+    pub markers: Markers,
+}
+
+/// Loudness measurements of a song, and the gain that normalizes it
+#[derive(Clone, Copy)]
+pub struct SongLevels {
+    /// Integrated loudness in LUFS
+    pub loudness: f32,
+
+    /// Peak level in dB: the true peak when measured, the sample peak when read from tags
+    pub peak: f32,
+
+    /// True if the loudness was read from ReplayGain or R128 tags instead of measured
+    pub tagged: bool,
+
+    /// Gain applied to the song when it plays, in dB
+    pub gain: f32,
 }
 
 impl Song {
@@ -13,17 +50,106 @@ impl Song {
         let mut decoder = Decoder::new(reader);
         let mut data = Vec::with_capacity(10_000_000);
 
+        // Channel count and sample rate are taken from the first frame
+        let mut format = None;
+
         loop {
             match decoder.next_frame() {
                 Ok(f) => {
+                    let (channels, _) = *format.get_or_insert((f.channels, f.sample_rate));
                     let converted = f.data.into_iter().map(|n| n as f32 / i16::MAX as f32);
-                    data.extend(converted);
+
+                    // A stream may switch between mono and stereo frames. Convert those to the
+                    // song's channel count so the data stays evenly interleaved.
+                    match (f.channels, channels) {
+                        (1, 2) => data.extend(converted.flat_map(|s| [s, s])),
+                        (2, 1) => {
+                            let frame: Vec<f32> = converted.collect();
+                            data.extend(frame.chunks(2).map(|lr| (lr[0] + lr[1]) / 2.));
+                        }
+                        _ => data.extend(converted),
+                    }
                 }
                 Err(Error::Eof) => break,
                 Err(e) => panic!("{}", e),
             }
         }
 
-        Song { data }
+        let (channels, sample_rate) = format.unwrap_or((1, 48000));
+        assert!(channels == 1 || channels == 2, "Unsupported channel count");
+
+        Song {
+            data: data.into(),
+            channels,
+            sample_rate: sample_rate as u32,
+            levels: SongLevels {
+                loudness: f32::NEG_INFINITY,
+                peak: f32::NEG_INFINITY,
+                tagged: false,
+                gain: 0.,
+            },
+            markers: Markers::default(),
+        }
+    }
+
+    /// Finds the song's loudness and peak level. Values from the file's tags are used where
+    /// present, the rest is measured as the song is heard, with mono songs on both sides. If a
+    /// target loudness in LUFS is given, sets the gain to bring the song to it.
+    pub fn analyze(&mut self, target: Option<f32>, tags: LoudnessTags) {
+        if let (Some(loudness), Some(peak)) = (tags.loudness, tags.peak) {
+            self.set_levels(target, loudness, peak, true);
+            return;
+        }
+
+        let mut meter = LoudnessMeter::new(self.sample_rate);
+        let mut true_peak = TruePeak::new();
+        let mut peak: f32 = 0.;
+
+        for i in 0..self.frames() {
+            let (l, r) = self.frame(i);
+            meter.add(l, r);
+            peak = peak.max(true_peak.process(l, r));
+        }
+
+        // Flush the last samples out of the true peak detector
+        for _ in 0..TP_DELAY {
+            peak = peak.max(true_peak.process(0., 0.));
+        }
+
+        let loudness = tags.loudness.unwrap_or(meter.integrated() as f32);
+        let peak = tags.peak.unwrap_or(20. * peak.log10());
+        self.set_levels(target, loudness, peak, tags.loudness.is_some());
+    }
+
+    fn set_levels(&mut self, target: Option<f32>, loudness: f32, peak: f32, tagged: bool) {
+        self.levels.loudness = loudness;
+        self.levels.peak = peak;
+        self.levels.tagged = tagged;
+        self.levels.gain = match target {
+            Some(target) if self.levels.loudness.is_finite() => target - self.levels.loudness,
+            _ => 0.,
+        };
+    }
+
+    /// Converts the song to the given sample rate, if it isn't already.
+    pub fn resample(&mut self, sample_rate: u32) {
+        if self.sample_rate != sample_rate {
+            self.data = resample(&self.data, self.channels, self.sample_rate, sample_rate).into();
+            self.sample_rate = sample_rate;
+        }
+    }
+
+    /// Number of frames (samples per channel) in the song
+    pub fn frames(&self) -> usize {
+        self.data.len() / self.channels
+    }
+
+    /// Returns the left and right samples of frame i. Mono songs are spread to both sides.
+    pub fn frame(&self, i: usize) -> (f32, f32) {
+        if self.channels == 1 {
+            (self.data[i], self.data[i])
+        } else {
+            (self.data[2 * i], self.data[2 * i + 1])
+        }
     }
 }
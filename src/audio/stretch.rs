@@ -0,0 +1,230 @@
+use super::song::Song;
+
+use std::f32::consts::PI;
+
+/// Length of a grain in ms. Longer grains smear drums, shorter ones make low notes rough.
+const GRAIN_MS: f32 = 40.;
+
+/// How far in ms a new grain may be moved to line up with the one it takes over from
+const SEARCH_MS: f32 = 10.;
+
+/// To keep lining up a grain cheap enough for the audio thread, it is first tried at offsets this
+/// many frames apart, comparing every other SEARCH_STRIDE frames, then around the best of those
+/// at every FINE_STEP frames, comparing every SEARCH_STRIDE frames
+const COARSE_STEP: usize = 16;
+const FINE_STEP: usize = 2;
+const SEARCH_STRIDE: usize = 4;
+
+/// Plays a song at a changed tempo and pitch, independently of each other (WSOLA). The output
+/// is made of overlapping windowed grains of the song: each grain reads the song at the pitch
+/// ratio, and a new one starts every half grain from the playhead, which moves through the song
+/// at the tempo ratio. Each new grain is moved a little to where it best lines up with the one
+/// fading out, so the overlaps do not sound hollow. At the native tempo and pitch the grains add
+/// up to the song itself, so they are not moved.
+#[derive(Clone, Copy)]
+pub struct Stretcher {
+    // Half the length of a grain in frames, and how far a new grain may be moved either way
+    half: usize,
+    search: usize,
+
+    // How far past its frame the playhead is, from 0 to 1
+    frac: f64,
+
+    // The grain fading out and the one fading in
+    grains: [Grain; 2],
+}
+
+#[derive(Clone, Copy)]
+struct Grain {
+    // Frame of the song the grain started at
+    start: f64,
+
+    // Frames played so far, up to a whole grain
+    age: usize,
+}
+
+impl Stretcher {
+    /// Creates a stretcher for songs at the given sample rate. It has to be reset to a frame
+    /// before use.
+    pub fn new(sample_rate: u32) -> Stretcher {
+        let frames = |ms: f32| (ms / 1000. * sample_rate as f32) as usize;
+        Stretcher {
+            half: frames(GRAIN_MS / 2.).max(1),
+            search: frames(SEARCH_MS),
+            frac: 0.,
+            grains: [Grain { start: 0., age: 0 }; 2],
+        }
+    }
+
+    /// Starts over at a frame, as after a jump
+    pub fn reset(&mut self, pos: usize, pitch: f64) {
+        self.frac = 0.;
+        self.grains = [
+            Grain {
+                start: pos as f64 - self.half as f64 * pitch,
+                age: self.half,
+            },
+            Grain {
+                start: pos as f64,
+                age: 0,
+            },
+        ];
+    }
+
+    /// Returns the next frame of the song, and moves the playhead pos on by tempo frames.
+    /// pitch is the ratio the song is played faster at within each grain.
+    pub fn next(&mut self, song: &Song, pos: &mut usize, tempo: f64, pitch: f64) -> (f32, f32) {
+        if self.grains[1].age >= self.half {
+            let at = *pos as f64 + self.frac;
+            let start = if tempo == 1. && pitch == 1. {
+                at
+            } else {
+                self.align(song, at, pitch)
+            };
+            self.grains = [self.grains[1], Grain { start, age: 0 }];
+        }
+
+        let (mut l, mut r) = (0., 0.);
+        for grain in &mut self.grains {
+            let window = 0.5 - 0.5 * (PI * grain.age as f32 / self.half as f32).cos();
+            let (gl, gr) = frame_at(song, grain.start + grain.age as f64 * pitch);
+            l += window * gl;
+            r += window * gr;
+            grain.age += 1;
+        }
+
+        self.frac += tempo;
+        let frames = self.frac.floor();
+        *pos += frames as usize;
+        self.frac -= frames;
+        (l, r)
+    }
+
+    /// Returns the start near at where a new grain best continues the grain that is about to
+    /// fade out. A change of either tempo or pitch puts the grains out of step with each other.
+    fn align(&self, song: &Song, at: f64, pitch: f64) -> f64 {
+        let search = self.search as isize;
+        let coarse = self.best_offset(
+            song,
+            at,
+            pitch,
+            (-search..=search).step_by(COARSE_STEP),
+            2 * SEARCH_STRIDE,
+        );
+
+        let around = (COARSE_STEP / 2) as isize;
+        let fine = self.best_offset(
+            song,
+            at,
+            pitch,
+            (coarse - around..=coarse + around).step_by(FINE_STEP),
+            SEARCH_STRIDE,
+        );
+        at + fine as f64
+    }
+
+    /// Returns the offset from at where a new grain best lines up with the grain that is about
+    /// to fade out, comparing the mono sum of what both would play every stride frames
+    fn best_offset(
+        &self,
+        song: &Song,
+        at: f64,
+        pitch: f64,
+        offsets: impl Iterator<Item = isize>,
+        stride: usize,
+    ) -> isize {
+        let old = self.grains[1];
+        let mono = |x: f64| {
+            let (l, r) = frame_at(song, x);
+            l + r
+        };
+
+        let mut best = (f32::NEG_INFINITY, 0);
+        for offset in offsets {
+            let start = at + offset as f64;
+            let (mut corr, mut energy) = (0., f32::EPSILON);
+            for t in (0..self.half).step_by(stride) {
+                let a = mono(old.start + (old.age + t) as f64 * pitch);
+                let b = mono(start + t as f64 * pitch);
+                corr += a * b;
+                energy += b * b;
+            }
+
+            let score = corr / energy.sqrt();
+            if score > best.0 {
+                best = (score, offset);
+            }
+        }
+        best.1
+    }
+}
+
+/// Returns the frame of a song at a fractional position, interpolated linearly. Outside the song
+/// is silent.
+fn frame_at(song: &Song, x: f64) -> (f32, f32) {
+    if x < 0. {
+        return (0., 0.);
+    }
+
+    let i = x as usize;
+    let f = (x - i as f64) as f32;
+    let frame = |i: usize| match i < song.frames() {
+        true => song.frame(i),
+        false => (0., 0.),
+    };
+    let ((l0, r0), (l1, r1)) = (frame(i), frame(i + 1));
+    (l0 + f * (l1 - l0), r0 + f * (r1 - r0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{Markers, SongLevels};
+
+    const RATE: u32 = 48000;
+
+    /// Makes a song of a second of a sine at the given frequency
+    fn sine(freq: f32) -> Song {
+        let data: Vec<f32> = (0..RATE as usize)
+            .map(|i| 0.5 * (2. * PI * freq * i as f32 / RATE as f32).sin())
+            .flat_map(|s| [s, s])
+            .collect();
+        Song {
+            data: data.into(),
+            channels: 2,
+            sample_rate: RATE,
+            levels: SongLevels {
+                loudness: 0.,
+                peak: 0.,
+                tagged: false,
+                gain: 0.,
+            },
+            markers: Markers::default(),
+        }
+    }
+
+    #[test]
+    fn key_shift_keeps_a_sine_even() {
+        // A sine that comes out with a period of 96 frames. Unaligned, each grain would start
+        // half a cycle off the one before.
+        let pitch = 2f64.powf(5. / 12.);
+        let song = sine(500. / pitch as f32);
+        let mut stretch = Stretcher::new(RATE);
+        let mut pos = 0;
+        stretch.reset(pos, pitch);
+        let output: Vec<f32> = (0..RATE / 2)
+            .map(|_| stretch.next(&song, &mut pos, 1., pitch).0)
+            .collect();
+
+        // Levels over two periods, well under the 20 ms between grains, skipping the first
+        // grain. Hollow overlaps dip between grains.
+        let levels: Vec<f32> = output[RATE as usize / 10..]
+            .chunks_exact(192)
+            .map(|w| (w.iter().map(|s| s * s).sum::<f32>() / 192.).sqrt())
+            .collect();
+        let expected = 0.5 / 2f32.sqrt();
+        for level in levels {
+            assert!((level / expected - 1.).abs() < 0.02, "level of {}", level);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+//! Reads loudness tags from the ID3v2 tag at the start of an mp3 file. ReplayGain tags are
+//! written by most library tools as TXXX frames, and R128 gain tags by some.
+
+/// Loudness ReplayGain 2 gains bring songs to, in LUFS
+const REPLAYGAIN_REFERENCE: f32 = -18.;
+
+/// Loudness R128 gains bring songs to, in LUFS
+const R128_REFERENCE: f32 = -23.;
+
+/// Loudness information found in a file's tags
+pub struct LoudnessTags {
+    /// Loudness in LUFS, worked out from the gain tag
+    pub loudness: Option<f32>,
+
+    /// Sample peak level in dBFS
+    pub peak: Option<f32>,
+}
+
+/// Reads the loudness and peak from a file's gain tags. With album set, album gains are used
+/// where present, otherwise track gains.
+pub fn read(data: &[u8], album: bool) -> LoudnessTags {
+    let frames = txxx_frames(data);
+    let find = |key: &str| {
+        frames
+            .iter()
+            .find(|(description, _)| description.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    };
+    let pick = |track: &str, album_key: &str| {
+        if album {
+            find(album_key).or_else(|| find(track))
+        } else {
+            find(track)
+        }
+    };
+
+    // ReplayGain gains look like "-6.54 dB", R128 gains are in 1/256 dB
+    let replaygain = pick("REPLAYGAIN_TRACK_GAIN", "REPLAYGAIN_ALBUM_GAIN")
+        .and_then(|v| parse_number(v.trim_end_matches(|c: char| c.is_alphabetic() || c == ' ')))
+        .map(|gain| REPLAYGAIN_REFERENCE - gain);
+    let r128 = pick("R128_TRACK_GAIN", "R128_ALBUM_GAIN")
+        .and_then(parse_number)
+        .map(|gain| R128_REFERENCE - gain / 256.);
+
+    // ReplayGain peaks are linear, with 1 at full scale
+    let peak = pick("REPLAYGAIN_TRACK_PEAK", "REPLAYGAIN_ALBUM_PEAK")
+        .and_then(parse_number)
+        .filter(|&peak| peak > 0.)
+        .map(|peak| 20. * peak.log10());
+
+    LoudnessTags {
+        loudness: replaygain.or(r128),
+        peak,
+    }
+}
+
+/// Parses a number, ignoring anything that is not one, including "NaN" and infinities
+fn parse_number(s: &str) -> Option<f32> {
+    s.trim().parse().ok().filter(|n: &f32| n.is_finite())
+}
+
+/// Returns the description and value of every TXXX (user defined text) frame of an ID3v2.3 or
+/// ID3v2.4 tag at the start of data. Returns nothing if there is no such tag.
+fn txxx_frames(data: &[u8]) -> Vec<(String, String)> {
+    let mut frames = Vec::new();
+
+    if data.len() < 10 || &data[..3] != b"ID3" {
+        return frames;
+    }
+    let version = data[3];
+    if version != 3 && version != 4 {
+        return frames;
+    }
+    let flags = data[5];
+    let end = (10 + syncsafe(&data[6..10])).min(data.len());
+
+    // Skip the extended header. Its size includes itself in 2.4, but not in 2.3.
+    let mut pos = 10;
+    if flags & 0x40 != 0 && pos + 4 <= end {
+        pos += match version {
+            3 => 4 + be_u32(&data[pos..pos + 4]),
+            _ => syncsafe(&data[pos..pos + 4]),
+        };
+    }
+
+    while pos + 10 <= end {
+        let id = &data[pos..pos + 4];
+
+        // Padding after the last frame
+        if id[0] == 0 {
+            break;
+        }
+
+        let size = match version {
+            3 => be_u32(&data[pos + 4..pos + 8]),
+            _ => syncsafe(&data[pos + 4..pos + 8]),
+        };
+        let format_flags = data[pos + 9];
+        let body_start = pos + 10;
+        pos = body_start + size;
+        if pos > end {
+            break;
+        }
+
+        if id == b"TXXX" {
+            // A 2.4 data length indicator comes before the text
+            let mut body = &data[body_start..pos];
+            if version == 4 && format_flags & 0x01 != 0 && body.len() >= 4 {
+                body = &body[4..];
+            }
+
+            if let Some(frame) = parse_txxx(body) {
+                frames.push(frame);
+            }
+        }
+    }
+
+    frames
+}
+
+/// Splits the body of a TXXX frame into its description and value
+fn parse_txxx(body: &[u8]) -> Option<(String, String)> {
+    let (&encoding, text) = body.split_first()?;
+
+    match encoding {
+        // Latin-1 or UTF-8, with a single 0 between the strings
+        0 | 3 => {
+            let split = text.iter().position(|&b| b == 0)?;
+            let decode = |bytes: &[u8]| match encoding {
+                0 => bytes.iter().map(|&b| b as char).collect(),
+                _ => String::from_utf8_lossy(bytes).into_owned(),
+            };
+            let value = text[split + 1..].split(|&b| b == 0).next()?;
+            Some((decode(&text[..split]), decode(value)))
+        }
+
+        // UTF-16 with a byte order mark or big endian, with a double 0 between the strings
+        1 | 2 => {
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect();
+            let mut strings = units.split(|&u| u == 0).map(|s| {
+                // Each string may start with its own byte order mark
+                match s.first() {
+                    Some(0xFEFF) => String::from_utf16_lossy(&s[1..]),
+                    Some(0xFFFE) => {
+                        let swapped: Vec<u16> = s[1..].iter().map(|u| u.swap_bytes()).collect();
+                        String::from_utf16_lossy(&swapped)
+                    }
+                    _ => String::from_utf16_lossy(s),
+                }
+            });
+            Some((strings.next()?, strings.next()?))
+        }
+
+        _ => None,
+    }
+}
+
+/// Reads a 28 bit ID3 syncsafe integer
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &b| (n << 7) | (b & 0x7F) as usize)
+}
+
+fn be_u32(bytes: &[u8]) -> usize {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes an ID3v2.4 tag of UTF-8 TXXX frames
+    fn tag(frames: &[(&str, &str)]) -> Vec<u8> {
+        let syncsafe = |n: usize| (0..4).rev().map(move |i| ((n >> (7 * i)) & 0x7F) as u8);
+
+        let mut body = Vec::new();
+        for (description, value) in frames {
+            let text = [&[3], description.as_bytes(), &[0], value.as_bytes()].concat();
+            body.extend(b"TXXX");
+            body.extend(syncsafe(text.len()));
+            body.extend([0, 0]);
+            body.extend(text);
+        }
+
+        let mut data = b"ID3\x04\x00\x00".to_vec();
+        data.extend(syncsafe(body.len()));
+        data.extend(body);
+        data
+    }
+
+    fn loudness(frames: &[(&str, &str)]) -> Option<f32> {
+        read(&tag(frames), false).loudness
+    }
+
+    #[test]
+    fn replaygain_gains_with_and_without_a_suffix() {
+        assert_eq!(
+            loudness(&[("REPLAYGAIN_TRACK_GAIN", "-6.50 dB")]),
+            Some(-11.5)
+        );
+        assert_eq!(loudness(&[("replaygain_track_gain", "+3 dB")]), Some(-21.));
+        assert_eq!(loudness(&[("REPLAYGAIN_TRACK_GAIN", "-6.5")]), Some(-11.5));
+    }
+
+    #[test]
+    fn r128_gains_are_in_256ths_of_a_db() {
+        assert_eq!(loudness(&[("R128_TRACK_GAIN", "-1280")]), Some(-18.));
+        assert_eq!(loudness(&[("R128_TRACK_GAIN", "512")]), Some(-25.));
+    }
+
+    #[test]
+    fn album_gains_are_used_when_asked_for() {
+        let data = tag(&[
+            ("REPLAYGAIN_TRACK_GAIN", "-6.50 dB"),
+            ("REPLAYGAIN_ALBUM_GAIN", "-4.00 dB"),
+            ("REPLAYGAIN_TRACK_PEAK", "0.5"),
+        ]);
+        assert_eq!(read(&data, false).loudness, Some(-11.5));
+        assert_eq!(read(&data, true).loudness, Some(-14.));
+
+        let peak = read(&data, true).peak.unwrap();
+        assert!((peak + 6.0206).abs() < 1e-3, "peak {}", peak);
+    }
+
+    #[test]
+    fn garbage_is_ignored() {
+        for value in [
+            "", "loud", "dB", "NaN", "inf dB", "1e999", "-6,5 dB", "\u{FFFD}",
+        ] {
+            assert_eq!(
+                loudness(&[("REPLAYGAIN_TRACK_GAIN", value)]),
+                None,
+                "{:?}",
+                value
+            );
+            assert_eq!(loudness(&[("R128_TRACK_GAIN", value)]), None, "{:?}", value);
+            let tags = read(&tag(&[("REPLAYGAIN_TRACK_PEAK", value)]), false);
+            assert_eq!(tags.peak, None, "{:?}", value);
+        }
+
+        // Broken tags read as having no gains
+        let mut truncated = tag(&[("REPLAYGAIN_TRACK_GAIN", "-6.50 dB")]);
+        truncated.truncate(20);
+        assert_eq!(read(&truncated, false).loudness, None);
+        assert_eq!(read(b"ID3", false).loudness, None);
+        assert_eq!(read(&[], false).loudness, None);
+    }
+}
@@ -0,0 +1,84 @@
+use std::f32::consts::PI;
+
+/// Number of taps of the interpolation filter
+const TAPS: usize = 12;
+
+/// Delay of the detector: the sample being checked is this many samples old
+pub const TP_DELAY: usize = TAPS / 2;
+
+/// True (inter-sample) peak detector for a stereo signal. Finds the peaks between samples by 4x
+/// oversampling with a windowed sinc.
+pub struct TruePeak {
+    // Last TAPS input samples of each channel, oldest first
+    history: [[f32; TAPS]; 2],
+
+    // Interpolation filter coefficients for the 3 points between two samples
+    coefs: [[f32; TAPS]; 3],
+}
+
+impl TruePeak {
+    pub fn new() -> TruePeak {
+        // Hann windowed sinc, evaluated at quarter sample offsets from the checked sample
+        let mut coefs = [[0.; TAPS]; 3];
+        for (k, phase) in coefs.iter_mut().enumerate() {
+            for (i, c) in phase.iter_mut().enumerate() {
+                let x = (TP_DELAY - 1) as f32 - i as f32 + (k + 1) as f32 / 4.;
+                let sinc = if x == 0. {
+                    1.
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                let window = 0.5 * (1. + (PI * x / TP_DELAY as f32).cos());
+                *c = sinc * window;
+            }
+        }
+
+        TruePeak {
+            history: [[0.; TAPS]; 2],
+            coefs,
+        }
+    }
+
+    /// Takes in one frame, and returns the highest absolute level of either channel at the sample
+    /// TP_DELAY frames ago and between it and the next sample.
+    pub fn process(&mut self, left: f32, right: f32) -> f32 {
+        let input = [left, right];
+        let mut peak: f32 = 0.;
+
+        for (c, history) in self.history.iter_mut().enumerate() {
+            history.copy_within(1.., 0);
+            history[TAPS - 1] = input[c];
+
+            peak = peak.max(history[TP_DELAY - 1].abs());
+            for phase in &self.coefs {
+                let interpolated: f32 = phase.iter().zip(history.iter()).map(|(c, h)| c * h).sum();
+                peak = peak.max(interpolated.abs());
+            }
+        }
+
+        peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_peak_between_samples() {
+        // A sine at a quarter of the sample rate, sampled 45 degrees off its peaks, so every
+        // sample is 3 dB below them
+        let sine = |i: usize| 0.5 * (PI / 2. * i as f32 + PI / 4.).sin();
+        let mut true_peak = TruePeak::new();
+        let mut peak: f32 = 0.;
+        let mut sample_peak: f32 = 0.;
+        for i in 0..1000 {
+            let s = sine(i);
+            sample_peak = sample_peak.max(s.abs());
+            peak = peak.max(true_peak.process(s, -s));
+        }
+
+        assert!((sample_peak - 0.5 / 2f32.sqrt()).abs() < 1e-3);
+        assert!((peak / 0.5 - 1.).abs() < 0.05, "true peak {}", peak);
+    }
+}
@@ -0,0 +1,286 @@
+use serde::Deserialize;
+use std::fs;
+
+/// Path of the config file, relative to the working directory like ./music
+const CONFIG_PATH: &str = "./config.toml";
+
+/// Mixer configuration, read from config.toml. Any missing section falls back to the setup
+/// used for the December 2020 show.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Input channel strips, in the order they are shown in the ui
+    #[serde(rename = "input")]
+    pub inputs: Vec<InputConfig>,
+
+    /// Carts of the cart wall, in the order they are shown in the ui
+    #[serde(rename = "cart")]
+    pub carts: Vec<CartConfig>,
+
+    /// Ports the left and right outputs are connected to
+    pub output: OutputConfig,
+
+    /// Initial settings of the output limiter
+    pub limiter: LimiterConfig,
+
+    /// Initial settings of the ducker, which turns the music down while keying inputs are live
+    pub ducker: DuckerConfig,
+
+    /// Loudness normalization of songs
+    pub normalize: NormalizeConfig,
+
+    /// Where and how the output is recorded
+    pub recorder: RecorderConfig,
+
+    /// Time constant of gain changes in ms, so moving a fader does not cause zipper noise.
+    /// 0 applies changes instantly.
+    pub smoothing: f32,
+
+    /// Length of the fades around play, pause, seek and load in ms, so they do not click
+    pub transport_fade: f32,
+}
+
+/// One input channel strip
+#[derive(Deserialize)]
+pub struct InputConfig {
+    /// Name shown above the strip's fader
+    pub name: String,
+
+    /// Name of the strip's jack input port
+    pub port: String,
+
+    /// Ports connected to the strip's input port on startup
+    #[serde(default)]
+    pub connect: Vec<String>,
+
+    /// True if the strip starts out keying the ducker
+    #[serde(default)]
+    pub duck: bool,
+}
+
+/// One cart of the cart wall: a short sample played over everything else
+#[derive(Deserialize)]
+pub struct CartConfig {
+    /// Name shown on the cart's button
+    pub name: String,
+
+    /// Path of the cart's mp3 file
+    pub file: String,
+
+    /// Key that triggers the cart, like "F1", "1" or "Q"
+    #[serde(default)]
+    pub key: Option<String>,
+
+    /// Initial gain in dB
+    #[serde(default)]
+    pub gain: f32,
+
+    /// What triggering the cart while it plays does
+    #[serde(default)]
+    pub mode: CartMode,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CartMode {
+    /// Starts it again from the beginning
+    #[default]
+    Retrigger,
+
+    /// Stops it
+    Stop,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+
+    // Ports the cue bus is connected to, usually headphones
+    pub cue: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct LimiterConfig {
+    /// Highest true peak level let through, in dBTP
+    pub ceiling: f32,
+
+    /// Time for gain reduction to recover, in ms
+    pub release: f32,
+
+    /// True to start with the limiter bypassed
+    pub bypass: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct DuckerConfig {
+    /// True to start with ducking on
+    pub enabled: bool,
+
+    /// Level in dBFS a keying input has to go over to duck the music
+    pub threshold: f32,
+
+    /// How far the music is turned down, in dB
+    pub depth: f32,
+
+    /// Times in ms the music takes to go down, stays down once the inputs are quiet, and takes
+    /// to come back
+    pub attack: f32,
+    pub hold: f32,
+    pub release: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct NormalizeConfig {
+    /// True to play every song at the target loudness
+    pub enabled: bool,
+
+    /// Loudness songs are brought to, in LUFS
+    pub target: f32,
+
+    /// True to use album gain tags where present instead of track gain tags
+    pub album: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RecorderConfig {
+    /// Directory recordings are saved to, created if missing
+    pub directory: String,
+
+    /// File format of recordings
+    pub format: RecordFormat,
+
+    /// Which tracks are recorded, and into how many files
+    pub tracks: RecordTracks,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    Wav,
+    Flac,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordTracks {
+    /// Only the output
+    Mix,
+
+    /// The output, the song and every input, each to its own file
+    Separate,
+
+    /// The output, the song and every input, all to one file
+    Multichannel,
+}
+
+impl Config {
+    /// Reads config.toml, or returns the default config if it does not exist. Panics if the
+    /// file is invalid.
+    pub fn load() -> Config {
+        let config = match fs::read_to_string(CONFIG_PATH) {
+            Ok(text) => toml::from_str(&text).expect("Invalid config.toml"),
+            Err(_) => Config::default(),
+        };
+
+        for (i, input) in config.inputs.iter().enumerate() {
+            assert!(
+                config.inputs[..i]
+                    .iter()
+                    .all(|other| other.port != input.port),
+                "config.toml: input port {} is used twice",
+                input.port
+            );
+        }
+
+        config
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        // jack_capture automatically connects when the port is found, so it is not needed here.
+        Config {
+            inputs: vec![
+                InputConfig {
+                    name: "Microphone".into(),
+                    port: "mic_in".into(),
+                    connect: vec!["system:capture_1".into(), "system:capture_2".into()],
+                    duck: true,
+                },
+                InputConfig {
+                    name: "Line in".into(),
+                    port: "line_in".into(),
+                    connect: vec!["line_in:capture_1".into(), "line_in:capture_2".into()],
+                    duck: false,
+                },
+            ],
+            carts: Vec::new(),
+            output: OutputConfig::default(),
+            limiter: LimiterConfig::default(),
+            ducker: DuckerConfig::default(),
+            normalize: NormalizeConfig::default(),
+            recorder: RecorderConfig::default(),
+            smoothing: 20.,
+            transport_fade: 10.,
+        }
+    }
+}
+
+impl Default for OutputConfig {
+    fn default() -> OutputConfig {
+        OutputConfig {
+            left: vec!["system:playback_1".into(), "darkice:left".into()],
+            right: vec!["system:playback_2".into(), "darkice:right".into()],
+            cue: Vec::new(),
+        }
+    }
+}
+
+impl Default for LimiterConfig {
+    fn default() -> LimiterConfig {
+        LimiterConfig {
+            ceiling: -1.,
+            release: 200.,
+            bypass: false,
+        }
+    }
+}
+
+impl Default for DuckerConfig {
+    fn default() -> DuckerConfig {
+        DuckerConfig {
+            enabled: false,
+            threshold: -30.,
+            depth: 12.,
+            attack: 20.,
+            hold: 500.,
+            release: 800.,
+        }
+    }
+}
+
+impl Default for NormalizeConfig {
+    fn default() -> NormalizeConfig {
+        NormalizeConfig {
+            enabled: true,
+            target: -16.,
+            album: false,
+        }
+    }
+}
+
+impl Default for RecorderConfig {
+    fn default() -> RecorderConfig {
+        RecorderConfig {
+            directory: "./recordings".into(),
+            format: RecordFormat::Flac,
+            tracks: RecordTracks::Mix,
+        }
+    }
+}
@@ -1,4 +1,5 @@
 mod audio;
+mod config;
 mod ui;
 
 use glium::{glutin, Surface};
@@ -8,9 +9,18 @@ use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 fn main() {
-    // Load music
+    // Offline render mode, which needs neither jack nor a window
     // =============================================================================================
-    let audio = audio::Audio::init();
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("render") {
+        audio::offline::render(&args[2..]);
+        return;
+    }
+
+    // Load config and music
+    // =============================================================================================
+    let config = config::Config::load();
+    let audio = audio::Audio::init(&config);
 
     // Make window
     // =============================================================================================
@@ -21,8 +31,8 @@ fn main() {
             .with_title("James' Mixer")
             .with_resizable(false)
             .with_inner_size(glutin::dpi::PhysicalSize {
-                width: 800,
-                height: 800,
+                width: ui::window_width(config.inputs.len()),
+                height: ui::window_height(config.inputs.len(), config.carts.len()),
             });
 
         glium::Display::new(window_builder, context, &event_loop).unwrap()
@@ -53,7 +63,7 @@ fn main() {
     // Attach to window
     platform.attach_window(
         imgui.io_mut(),
-        &display.gl_window().window(),
+        display.gl_window().window(),
         imgui_winit_support::HiDpiMode::Locked(1.0),
     );
 
@@ -61,12 +71,39 @@ fn main() {
     // =============================================================================================
     let mut ui_state = ui::UIState {
         window_size: [0.; 2],
-        mic_volume: 0.,
-        line_volume: 0.,
-        song_volume: 0.,
+        inputs: config
+            .inputs
+            .iter()
+            .map(|input| ui::StripState {
+                duck: input.duck,
+                ..Default::default()
+            })
+            .collect(),
+        music: Default::default(),
+        master_volume: 0.,
+        output_meters: Default::default(),
+        limiter_enabled: !config.limiter.bypass,
+        limiter_ceiling: config.limiter.ceiling,
+        limiter_release: config.limiter.release,
+        ducker_enabled: config.ducker.enabled,
+        ducker_threshold: config.ducker.threshold,
+        ducker_depth: config.ducker.depth,
+        ducker_attack: config.ducker.attack,
+        ducker_hold: config.ducker.hold,
+        ducker_release: config.ducker.release,
         selected_song: 0,
-        jump_time: [0; 2],
-        loaded_song: imgui::ImString::new("Load song below"),
+        song_labels: ui::song_labels(&audio),
+        queue: Vec::new(),
+        selected_queued: 0,
+        queue_seen: 0,
+        end_mode: audio::EndMode::Next,
+        overlap: 0.,
+        decks: Default::default(),
+        cue_volume: 0.,
+        crossfader: 0.,
+        crossfade_curve: audio::CrossfadeCurve::Power,
+        recording_file: imgui::ImString::default(),
+        carts: ui::cart_states(&config.carts),
     };
 
     // Previous frame (pf) start time
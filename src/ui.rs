@@ -1,23 +1,222 @@
-use crate::audio::Audio;
+use crate::audio::{
+    gain_to_db, parse_time, samples_to_minsec, Audio, CrossfadeCurve, EndMode, Marker, Overview,
+    Strip, CUE, DECKS, MIN_DB,
+};
+use crate::config::CartConfig;
+use glium::glutin::event::VirtualKeyCode;
 use imgui::*;
 
 pub struct UIState {
     // The size of the actual window, used to update imgui window size
     pub window_size: [f32; 2],
 
-    // The percentage volume (0-100+) of the 3 inputs
-    pub mic_volume: f32,
-    pub line_volume: f32,
-    pub song_volume: f32,
+    // State of each input strip and the music strip
+    pub inputs: Vec<StripState>,
+    pub music: StripState,
+
+    // Master output volume in dB
+    pub master_volume: f32,
+
+    // Meters of the left and right outputs
+    pub output_meters: [MeterState; 2],
+
+    // Output limiter settings: enabled, ceiling in dBTP, release in ms
+    pub limiter_enabled: bool,
+    pub limiter_ceiling: f32,
+    pub limiter_release: f32,
+
+    // Ducker settings: enabled, threshold in dBFS, depth in dB, and times in ms
+    pub ducker_enabled: bool,
+    pub ducker_threshold: f32,
+    pub ducker_depth: f32,
+    pub ducker_attack: f32,
+    pub ducker_hold: f32,
+    pub ducker_release: f32,
 
     // Currently selected song list index
     pub selected_song: i32,
 
-    // Jump-to-time target
-    pub jump_time: [i32; 2],
+    // Song list entries, showing each song's levels
+    pub song_labels: Vec<ImString>,
+
+    // Songs in the play queue, next first, and the selected one
+    pub queue: Vec<ImString>,
+    pub selected_queued: i32,
+
+    // Songs taken off the queue by the decks that the queue above accounts for
+    pub queue_seen: usize,
+
+    // What decks do when their song ends, and the seconds the next song overlaps it
+    pub end_mode: EndMode,
+    pub overlap: f32,
+
+    // State of each playback deck, and of the cue player last
+    pub decks: [DeckState; DECKS + 1],
+
+    // Volume of the cue bus in dB
+    pub cue_volume: f32,
+
+    // Crossfader position from 0 (deck A) to 1 (deck B), and its curve
+    pub crossfader: f32,
+    pub crossfade_curve: CrossfadeCurve,
+
+    // Name of the file last recorded to
+    pub recording_file: ImString,
+
+    // State of each cart of the cart wall
+    pub carts: Vec<CartState>,
+}
+
+/// Ui state of one channel strip
+pub struct StripState {
+    // Volume in dB
+    pub volume: f32,
+
+    // Switches. duck is only used by input strips.
+    pub mute: bool,
+    pub solo: bool,
+    pub invert: bool,
+    pub duck: bool,
+
+    // Meter of the strip's input
+    pub meter: MeterState,
+}
+
+/// Ui state of one playback deck or the cue player
+pub struct DeckState {
+    // Jump-to-time target, as typed: seconds or minutes and seconds, like 1:30.5
+    jump_time: ImString,
+
+    // Position the timestamp slider is held at while it is dragged, in samples
+    scrub: Option<u64>,
+
+    // Length of the fade out button's fade, in seconds
+    fade_time: f32,
+
+    // Tempo in percent off the song's own, and pitch shift in semitones
+    tempo: f32,
+    pitch: f32,
+}
+
+/// Ui state of one cart
+pub struct CartState {
+    // Name shown on the cart's button
+    name: ImString,
+
+    // Key that triggers the cart as an imgui key index, and the name it was given by
+    key: Option<(u32, String)>,
+
+    // Gain in dB
+    gain: f32,
+}
+
+/// Ui state of a level meter
+pub struct MeterState {
+    // Peak level shown in dB, which falls back slowly after a peak
+    peak: f32,
+
+    // Highest recent peak in dB, and seconds left to hold it
+    hold: f32,
+    hold_time: f32,
 
-    // Currently loaded song name
-    pub loaded_song: ImString,
+    // True once the signal has reached full scale, until the meter is clicked
+    clip: bool,
+}
+
+impl Default for StripState {
+    /// Strips start with the fader all the way down and every switch off
+    fn default() -> StripState {
+        StripState {
+            volume: MIN_DB,
+            mute: false,
+            solo: false,
+            invert: false,
+            duck: false,
+            meter: Default::default(),
+        }
+    }
+}
+
+impl Default for DeckState {
+    /// Decks start with no song loaded, 5 second fades and songs at their own tempo and pitch
+    fn default() -> DeckState {
+        DeckState {
+            jump_time: ImString::with_capacity(16),
+            scrub: None,
+            fade_time: 5.,
+            tempo: 0.,
+            pitch: 0.,
+        }
+    }
+}
+
+impl Default for MeterState {
+    /// Meters start out showing silence
+    fn default() -> MeterState {
+        MeterState {
+            peak: MIN_DB,
+            hold: MIN_DB,
+            hold_time: 0.,
+            clip: false,
+        }
+    }
+}
+
+/// Width of a channel strip column
+const STRIP_WIDTH: f32 = 150.;
+
+/// Width of a deck or cue player column
+const DECK_WIDTH: f32 = 260.;
+
+/// Width of the controls column
+const CONTROLS_WIDTH: f32 = 300.;
+
+/// Size of a fader
+const FADER_SIZE: [f32; 2] = [100., 300.];
+
+/// Width of a level meter bar
+const METER_WIDTH: f32 = 8.;
+
+/// Rate the shown peak level falls at, in dB per second
+const PEAK_FALL: f32 = 20.;
+
+/// Time the highest peak stays marked, in seconds
+const PEAK_HOLD: f32 = 1.5;
+
+/// Size of the waveform overview of a deck's song
+const OVERVIEW_SIZE: [f32; 2] = [DECK_WIDTH - 20., 60.];
+
+/// Color of the loop points and their buttons
+const LOOP_COLOR: [f32; 4] = [0.3, 0.8, 1., 1.];
+
+/// Size of a cart button
+const CART_SIZE: [f32; 2] = [140., 50.];
+
+/// Height of the window without a cart wall
+const BASE_HEIGHT: f32 = 800.;
+
+/// Returns the window width needed to fit the given number of input strips, the music and
+/// master strips, the decks, the cue player and the controls.
+pub fn window_width(inputs: usize) -> u32 {
+    let decks = (DECKS + 1) as f32 * DECK_WIDTH;
+    ((inputs + 2) as f32 * STRIP_WIDTH + decks + CONTROLS_WIDTH) as u32
+}
+
+/// Returns the window height needed to fit the cart wall above the song list
+pub fn window_height(inputs: usize, carts: usize) -> u32 {
+    if carts == 0 {
+        return BASE_HEIGHT as u32;
+    }
+
+    // A row of buttons with gain sliders below, and the heading, with imgui's default spacing
+    let per_row = carts_per_row(window_width(inputs) as f32, 8.);
+    let rows = carts.div_ceil(per_row);
+    (BASE_HEIGHT + 40. + rows as f32 * (CART_SIZE[1] + 40.)) as u32
+}
+
+/// Returns how many carts fit in a row of the given width
+fn carts_per_row(width: f32, spacing: f32) -> usize {
+    ((width / (CART_SIZE[0] + spacing)) as usize).max(1)
 }
 
 pub fn draw_ui(ui: &mut imgui::Ui, state: &mut UIState, audio: &Audio) {
@@ -38,144 +237,1030 @@ pub fn draw_ui(ui: &mut imgui::Ui, state: &mut UIState, audio: &Audio) {
             ui.text("Volume Adjustment:");
             ui.next_column();
             ui.text("Music Controls:");
+            if let Some(rate) = audio.rate_mismatch() {
+                ui.same_line(0.);
+                ui.text_colored(
+                    [1., 0.2, 0.2, 1.],
+                    format!(
+                        "Songs and carts stopped: jack changed to {} Hz, but they are at {} \
+                         Hz. Restart the mixer to resample them.",
+                        rate,
+                        audio.sample_rate()
+                    ),
+                );
+            }
 
             ui.separator();
 
             // Volume columns
             // =====================================================================================
-            // 4 columns for 3 input volume + 1 music control column
-            ui.columns(4, im_str!("##Inputs and Controls"), false);
+            // One column per input strip, + 1 music volume column + 1 master volume column
+            // + 1 column per deck + 1 cue column + 1 controls column
+            let columns = state.inputs.len() as i32 + DECKS as i32 + 4;
+            ui.columns(columns, im_str!("##Inputs and Controls"), false);
 
-            // Microphone volume column
+            // Input strip columns
             // =====================================================================================
-            ui.set_current_column_width(150.);
-            ui.text("Microphone");
-            let changed = VerticalSlider::new(im_str!("##Mic volume"), [100., 300.])
-                .range(0.0..=500.0)
-                .flags(SliderFlags::LOGARITHMIC)
-                .display_format(im_str!("%.0f%%"))
-                .build(ui, &mut state.mic_volume);
-
-            if changed {
-                audio.set_mic_volume(state.mic_volume);
+            for (i, name) in audio.input_names().iter().enumerate() {
+                let strip_state = &mut state.inputs[i];
+                draw_strip(ui, audio, Strip::Input(i), name, 14., strip_state);
+                ui.next_column();
             }
 
-            // Line in volume column
+            // Music strip column
+            // =====================================================================================
+            draw_strip(
+                ui,
+                audio,
+                Strip::Music,
+                im_str!("Music"),
+                20.,
+                &mut state.music,
+            );
+
+            // Master volume column
             // =====================================================================================
             ui.next_column();
-            ui.set_current_column_width(150.);
-            ui.text("Line in");
-            let changed = VerticalSlider::new(im_str!("##Line in volume"), [100., 300.])
-                .range(0.0..=500.0)
+            ui.set_current_column_width(STRIP_WIDTH);
+            ui.text("Master");
+            let top_left = ui.cursor_screen_pos();
+            if draw_fader(ui, im_str!("##Master volume"), 6., &mut state.master_volume) {
+                audio.set_master_volume(state.master_volume);
+            }
+
+            // Left and right output meters beside the fader
+            let levels = audio.output_meters();
+            for (i, (meter, level)) in state.output_meters.iter_mut().zip(levels).enumerate() {
+                let x = top_left[0] + FADER_SIZE[0] + 26. + i as f32 * (METER_WIDTH + 2.);
+                draw_meter(
+                    ui,
+                    &im_str!("##Output meter {}", i),
+                    [x, top_left[1]],
+                    meter,
+                    level,
+                );
+            }
+
+            // Output limiter controls, below the master fader
+            if ui.checkbox(im_str!("Limiter"), &mut state.limiter_enabled) {
+                audio.set_limiter_bypass(!state.limiter_enabled);
+            }
+
+            // Gain reduction bar, full at 12 dB
+            let reduction = audio.limiter_reduction();
+            ProgressBar::new(reduction / 12.)
+                .size([FADER_SIZE[0], 0.])
+                .overlay_text(&im_str!("GR {:.1} dB", reduction))
+                .build(ui);
+
+            let width_tok = ui.push_item_width(FADER_SIZE[0]);
+            let changed = Slider::new(im_str!("##Limiter ceiling"))
+                .range(-12.0..=0.0)
+                .display_format(im_str!("%.1f dBTP"))
+                .build(ui, &mut state.limiter_ceiling);
+
+            if changed {
+                audio.set_limiter_ceiling(state.limiter_ceiling);
+            }
+
+            let changed = Slider::new(im_str!("##Limiter release"))
+                .range(10.0..=2000.0)
                 .flags(SliderFlags::LOGARITHMIC)
-                .display_format(im_str!("%.0f%%"))
-                .build(ui, &mut state.line_volume);
+                .display_format(im_str!("%.0f ms"))
+                .build(ui, &mut state.limiter_release);
 
             if changed {
-                audio.set_line_volume(state.line_volume);
+                audio.set_limiter_release(state.limiter_release);
             }
+            width_tok.pop(ui);
 
-            // Music volume column
+            // Deck columns side by side, then the cue player column
             // =====================================================================================
-            ui.next_column();
-            ui.set_current_column_width(150.);
-            ui.text("Music");
-            let changed = VerticalSlider::new(im_str!("##Music volume"), [100., 300.])
-                .range(0.0..=1000.0)
-                .flags(SliderFlags::LOGARITHMIC)
-                .display_format(im_str!("%.0f%%"))
-                .build(ui, &mut state.song_volume);
+            // When the next song overlaps the end of this one, the overview marks where it starts
+            let overlap = match state.end_mode {
+                EndMode::Next | EndMode::Loop if state.overlap > 0. => Some(state.overlap),
+                _ => None,
+            };
+            for (deck, deck_state) in state.decks.iter_mut().enumerate() {
+                ui.next_column();
+                let overlap = if deck == CUE { None } else { overlap };
+                draw_deck(ui, audio, deck, deck_state, overlap);
+            }
 
+            // Cue bus volume, below the cue player
+            let width_tok = ui.push_item_width(170.);
+            let format = if state.cue_volume <= MIN_DB {
+                im_str!("-inf dB")
+            } else {
+                im_str!("%+.1f dB")
+            };
+            let changed = Slider::new(im_str!("Cue volume"))
+                .range(MIN_DB..=6.)
+                .display_format(format)
+                .build(ui, &mut state.cue_volume);
             if changed {
-                audio.set_song_volume(state.song_volume);
+                audio.set_cue_volume(state.cue_volume);
             }
+            width_tok.pop(ui);
 
-            // Music controls column
+            // Controls column
             // =====================================================================================
             ui.next_column();
-            if ui.button(im_str!("Pause"), [80., 30.]) {
-                audio.set_paused(true);
+
+            // Draw crossfader, from deck A on the left to deck B on the right
+            ui.text("Crossfader");
+            let width_tok = ui.push_item_width(200.);
+            let changed = Slider::new(im_str!("A##Crossfader"))
+                .range(0.0..=1.0)
+                .display_format(im_str!("B"))
+                .build(ui, &mut state.crossfader);
+            if changed {
+                audio.set_crossfader(state.crossfader);
             }
 
-            ui.same_line(80. + 3. * ui.clone_style().frame_padding[0]);
-            if ui.button(im_str!("Play"), [80., 30.]) {
-                audio.set_paused(false);
+            let names: Vec<ImString> = CrossfadeCurve::ALL
+                .iter()
+                .map(|curve| ImString::new(curve.name()))
+                .collect();
+            let names: Vec<&ImStr> = names.iter().map(|n| n.as_ref()).collect();
+            let mut curve = CrossfadeCurve::ALL
+                .iter()
+                .position(|&c| c == state.crossfade_curve)
+                .unwrap();
+            if ComboBox::new(im_str!("Curve")).build_simple_string(ui, &mut curve, &names) {
+                state.crossfade_curve = CrossfadeCurve::ALL[curve];
+                audio.set_crossfade_curve(state.crossfade_curve);
             }
+            width_tok.pop(ui);
 
-            // Draw loaded song
-            ui.text("Loaded song:");
-            ui.same_line(
-                ui.calc_text_size(im_str!("Loaded song:"), false, 0.0)[0]
-                    + 3. * ui.clone_style().frame_padding[0],
-            );
-            ui.text(&state.loaded_song);
+            if ui.button(im_str!("Center"), [80., 0.]) {
+                state.crossfader = 0.5;
+                audio.set_crossfader(state.crossfader);
+            }
 
-            // Draw paused/playing
-            ui.text(if audio.get_paused() {
-                "Status: Paused"
-            } else {
-                "Status: Playing"
-            });
+            // Draw ducker, which turns the music down while the strips set to duck it are live
+            ui.separator();
+            if ui.checkbox(im_str!("Auto-duck music"), &mut state.ducker_enabled) {
+                audio.set_ducker_enabled(state.ducker_enabled);
+            }
 
-            // Draw timestamp
-            let ((ts_m, ts_s), (mt_m, mt_s)) = audio.music_timestamp();
-            ui.text(format!(
-                "Timestamp: {:02}:{:02} / {:02}:{:02}",
-                ts_m, ts_s, mt_m, mt_s
-            ));
+            // Gain reduction bar, full at the ducker's depth
+            let reduction = audio.ducker_reduction();
+            ProgressBar::new(reduction / state.ducker_depth.max(1.))
+                .size([200., 0.])
+                .overlay_text(&im_str!("GR {:.1} dB", reduction))
+                .build(ui);
+
+            let width_tok = ui.push_item_width(200.);
+            let changed = Slider::new(im_str!("Threshold##Ducker"))
+                .range(-60.0..=0.0)
+                .display_format(im_str!("%.0f dBFS"))
+                .build(ui, &mut state.ducker_threshold);
+            if changed {
+                audio.set_ducker_threshold(state.ducker_threshold);
+            }
 
-            let (samples, max_samples) = audio.music_samples();
-            let (mut samples, max_samples) = (samples as u64, max_samples as u64);
-            Slider::new(im_str!("##Timestamp slider"))
-                .range(0..=max_samples)
-                .display_format(im_str!(""))
-                .build(ui, &mut samples);
+            let changed = Slider::new(im_str!("Depth##Ducker"))
+                .range(0.0..=40.0)
+                .display_format(im_str!("%.0f dB"))
+                .build(ui, &mut state.ducker_depth);
+            if changed {
+                audio.set_ducker_depth(state.ducker_depth);
+            }
 
-            // Draw jump-to-time
-            if ui.button(im_str!("Jump to"), [80., 30.]) && state.jump_time != [0, 0] {
-                audio.jump_song(state.jump_time[0] as usize, state.jump_time[1] as usize);
-                state.jump_time = [0; 2];
+            let mut changed = Slider::new(im_str!("Attack##Ducker"))
+                .range(1.0..=500.0)
+                .flags(SliderFlags::LOGARITHMIC)
+                .display_format(im_str!("%.0f ms"))
+                .build(ui, &mut state.ducker_attack);
+            changed |= Slider::new(im_str!("Hold##Ducker"))
+                .range(0.0..=3000.0)
+                .display_format(im_str!("%.0f ms"))
+                .build(ui, &mut state.ducker_hold);
+            changed |= Slider::new(im_str!("Release##Ducker"))
+                .range(10.0..=5000.0)
+                .flags(SliderFlags::LOGARITHMIC)
+                .display_format(im_str!("%.0f ms"))
+                .build(ui, &mut state.ducker_release);
+            if changed {
+                audio.set_ducker_times(
+                    state.ducker_attack,
+                    state.ducker_hold,
+                    state.ducker_release,
+                );
+            }
+            width_tok.pop(ui);
+
+            // Draw output loudness
+            ui.separator();
+            let loudness = audio.loudness();
+            ui.text(format!("Momentary:  {}", format_lufs(loudness.momentary)));
+            ui.text(format!("Short-term: {}", format_lufs(loudness.short_term)));
+            ui.text(format!("Integrated: {}", format_lufs(loudness.integrated)));
+            ui.text(format!("Range:      {:.1} LU", loudness.range));
+            if ui.button(im_str!("Reset loudness"), [120., 30.]) {
+                audio.reset_loudness();
+            }
+
+            // Draw recorder
+            ui.separator();
+            if ui.button(im_str!("Record"), [80., 30.]) {
+                let path = audio.start_recording();
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                state.recording_file = ImString::new(name);
             }
 
             ui.same_line(80. + 3. * ui.clone_style().frame_padding[0]);
+            if ui.button(im_str!("Stop"), [80., 30.]) {
+                audio.stop_recording();
+            }
 
-            let width_tok = ui.push_item_width(50.);
-            InputInt2::new(ui, im_str!("##Jump time"), &mut state.jump_time).build();
-            width_tok.pop(ui);
+            match audio.recording_status() {
+                Some(status) => {
+                    let seconds = status.seconds as u64;
+                    ui.text(format!("Recording: {}", state.recording_file));
+                    ui.text(format!(
+                        "{:02}:{:02}:{:02}  {:.1} MB",
+                        seconds / 3600,
+                        seconds / 60 % 60,
+                        seconds % 60,
+                        status.bytes as f64 / 1e6
+                    ));
+                    if status.dropped > 0 {
+                        ui.text_colored(
+                            [1., 0.2, 0.2, 1.],
+                            format!("Dropped {} frames", status.dropped),
+                        );
+                    }
+                }
+                None => ui.text("Not recording"),
+            }
+            if let Some(notice) = audio.recording_notice() {
+                let color = ui.push_style_color(StyleColor::Text, [1., 0.8, 0.2, 1.]);
+                ui.text_wrapped(&ImString::new(notice));
+                color.pop(ui);
+            }
+
+            // Cart wall
+            // =====================================================================================
+            if !state.carts.is_empty() {
+                ui.columns(1, im_str!("##Cart section"), false);
+                ui.separator();
+                ui.text("Carts");
+                ui.same_line(0.);
+                if ui.button(im_str!("Stop carts"), [120., 0.]) {
+                    audio.stop_carts();
+                }
+
+                draw_carts(ui, state, audio);
+            }
 
-            // Music selection box
+            // Music selection box and play queue
             // =====================================================================================
             let song_list = audio.song_list();
-            ui.columns(1, im_str!("##Selection section"), false);
+            ui.columns(2, im_str!("##Selection section"), false);
             ui.separator();
             ui.text("Song Selection");
 
-            // Load song button
-            if ui.button(im_str!("Load"), [80., 30.]) {
-                let song_name = song_list[state.selected_song as usize];
+            // Load song buttons, one per deck
+            for deck in 0..DECKS {
+                if deck > 0 {
+                    ui.same_line(0.);
+                }
 
-                // Tell audio system to load song
-                audio.load_song(song_name);
+                if ui.button(&im_str!("Load {}", deck_name(deck)), [80., 30.]) {
+                    let song_name = song_list[state.selected_song as usize];
+
+                    // Tell audio system to load song
+                    audio.load_song(deck, song_name);
+                }
+            }
+
+            ui.same_line(0.);
+            if ui.button(im_str!("Queue"), [80., 30.]) {
+                let song_name = song_list[state.selected_song as usize];
+                state.queue.push(song_name.to_owned());
+                audio.set_queue(&state.queue, state.queue_seen);
+            }
 
-                // Display loaded song in controls column
-                state.loaded_song = song_name.to_owned();
+            // Preview on the cue bus
+            ui.same_line(0.);
+            if ui.button(im_str!("Cue"), [80., 30.]) {
+                let song_name = song_list[state.selected_song as usize];
+                audio.load_song(CUE, song_name);
+                audio.set_paused(CUE, false);
             }
 
             ChildWindow::new(0).build(ui, || {
-                // Setup width for list box
-                let xpad = ui.clone_style().frame_padding[0];
-                let width = state.window_size[0] - xpad;
-                let width_tok = ui.push_item_width(width);
+                // List box fills the column
+                let width_tok = ui.push_item_width(-1.);
 
+                let labels: Vec<&ImStr> = state.song_labels.iter().map(|l| l.as_ref()).collect();
                 ui.list_box(
                     im_str!("##Song selector"),
                     &mut state.selected_song,
-                    song_list.as_slice(),
-                    song_list.len() as i32,
+                    labels.as_slice(),
+                    labels.len() as i32,
                 );
 
                 // Clear width
                 width_tok.pop(ui);
-            })
+            });
+
+            ui.next_column();
+            draw_queue(ui, state, audio);
         });
 }
+
+/// Draws the play queue with buttons to reorder and remove songs, and the settings for going
+/// from one song to the next. Also drops the songs the decks took off the queue.
+fn draw_queue(ui: &Ui, state: &mut UIState, audio: &Audio) {
+    let advanced = audio.queue_advanced();
+    let taken = (advanced - state.queue_seen).min(state.queue.len());
+    state.queue.drain(..taken);
+    state.queue_seen = advanced;
+
+    ui.text("Queue");
+
+    // Going from one song to the next
+    let width_tok = ui.push_item_width(80.);
+    let names: Vec<ImString> = EndMode::ALL
+        .iter()
+        .map(|mode| ImString::new(mode.name()))
+        .collect();
+    let names: Vec<&ImStr> = names.iter().map(|n| n.as_ref()).collect();
+    let mut mode = EndMode::ALL
+        .iter()
+        .position(|&m| m == state.end_mode)
+        .unwrap();
+    if ComboBox::new(im_str!("At song end")).build_simple_string(ui, &mut mode, &names) {
+        state.end_mode = EndMode::ALL[mode];
+        audio.set_end_mode(state.end_mode);
+    }
+
+    ui.same_line(0.);
+    let format = if state.overlap > 0. {
+        im_str!("%.1f s")
+    } else {
+        im_str!("gapless")
+    };
+    let changed = Slider::new(im_str!("Overlap"))
+        .range(0.0..=10.)
+        .display_format(format)
+        .build(ui, &mut state.overlap);
+    if changed {
+        audio.set_overlap(state.overlap);
+    }
+    width_tok.pop(ui);
+
+    // Editing the selected song
+    let selected = state.selected_queued as usize;
+    let mut edited = false;
+    if ui.button(im_str!("Up"), [60., 0.]) && selected > 0 && selected < state.queue.len() {
+        state.queue.swap(selected, selected - 1);
+        state.selected_queued -= 1;
+        edited = true;
+    }
+
+    ui.same_line(0.);
+    if ui.button(im_str!("Down"), [60., 0.]) && selected + 1 < state.queue.len() {
+        state.queue.swap(selected, selected + 1);
+        state.selected_queued += 1;
+        edited = true;
+    }
+
+    ui.same_line(0.);
+    if ui.button(im_str!("Remove"), [60., 0.]) && selected < state.queue.len() {
+        state.queue.remove(selected);
+        edited = true;
+    }
+
+    ui.same_line(0.);
+    if ui.button(im_str!("Clear"), [60., 0.]) {
+        state.queue.clear();
+        edited = true;
+    }
+
+    // Preview the selected song, or the next one if none is selected, on the cue bus
+    ui.same_line(0.);
+    if ui.button(im_str!("Cue##Queued"), [60., 0.]) {
+        if let Some(name) = state.queue.get(selected).or_else(|| state.queue.first()) {
+            audio.load_song(CUE, name);
+            audio.set_paused(CUE, false);
+        }
+    }
+
+    if edited {
+        audio.set_queue(&state.queue, state.queue_seen);
+    }
+
+    ChildWindow::new(1).build(ui, || {
+        let width_tok = ui.push_item_width(-1.);
+
+        let labels: Vec<ImString> = state
+            .queue
+            .iter()
+            .enumerate()
+            .map(|(i, name)| im_str!("{}. {}", i + 1, name))
+            .collect();
+        let labels: Vec<&ImStr> = labels.iter().map(|l| l.as_ref()).collect();
+        ui.list_box(
+            im_str!("##Queue"),
+            &mut state.selected_queued,
+            labels.as_slice(),
+            labels.len() as i32,
+        );
+
+        width_tok.pop(ui);
+    });
+}
+
+/// Returns the song list entries: each song's name with its loudness, peak and the gain it plays
+/// with.
+pub fn song_labels(audio: &Audio) -> Vec<ImString> {
+    audio
+        .song_list()
+        .into_iter()
+        .map(|name| {
+            let levels = audio.song_levels(name);
+            im_str!(
+                "{}  ({:.1} LUFS{}, peak {:.1} dB, gain {:+.1} dB)",
+                name,
+                levels.loudness,
+                if levels.tagged { " tagged" } else { "" },
+                levels.peak,
+                levels.gain
+            )
+        })
+        .collect()
+}
+
+/// Returns the ui state of the carts in the config. Panics if a cart's key is not one the ui
+/// knows.
+pub fn cart_states(carts: &[CartConfig]) -> Vec<CartState> {
+    carts
+        .iter()
+        .map(|cart| CartState {
+            name: ImString::new(&cart.name),
+            key: cart.key.as_ref().map(|key| match key_index(key) {
+                Some(index) => (index, key.clone()),
+                None => panic!("config.toml: unknown key {} for cart {}", key, cart.name),
+            }),
+            gain: cart.gain,
+        })
+        .collect()
+}
+
+/// Returns the imgui key index of a key name: a letter, a digit, F1 to F12, or Numpad0 to
+/// Numpad9. imgui-winit-support indexes keys by their winit key code.
+fn key_index(name: &str) -> Option<u32> {
+    use VirtualKeyCode::*;
+    let letters = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    let digits = [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    let numpad = [
+        Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    ];
+    let functions = [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12];
+
+    let name = name.to_ascii_uppercase();
+    let number = |prefix: &str| name.strip_prefix(prefix)?.parse::<usize>().ok();
+    let code = match name.as_bytes() {
+        [c @ b'A'..=b'Z'] => letters[(c - b'A') as usize],
+        [c @ b'0'..=b'9'] => digits[(c - b'0') as usize],
+        _ if name.starts_with("NUMPAD") => *numpad.get(number("NUMPAD")?)?,
+        _ if name.starts_with('F') => *functions.get(number("F")?.checked_sub(1)?)?,
+        _ => return None,
+    };
+    Some(code as u32)
+}
+
+/// Returns the letter a deck is known by
+fn deck_name(deck: usize) -> char {
+    (b'A' + deck as u8) as char
+}
+
+/// Draws the column of a playback deck or the cue player: its transport, loaded song, waveform
+/// and timestamp. overlap is the seconds the next song starts before this one ends, if any.
+fn draw_deck(ui: &Ui, audio: &Audio, deck: usize, state: &mut DeckState, overlap: Option<f32>) {
+    let name = deck_name(deck);
+    let spacing = 80. + 3. * ui.clone_style().frame_padding[0];
+
+    ui.set_current_column_width(DECK_WIDTH);
+    if deck == CUE {
+        ui.text("Cue (off air)");
+    } else {
+        ui.text(format!("Deck {}", name));
+    }
+
+    if ui.button(&im_str!("Pause##Deck {}", name), [80., 30.]) {
+        audio.set_paused(deck, true);
+    }
+
+    ui.same_line(spacing);
+    if ui.button(&im_str!("Play##Deck {}", name), [80., 30.]) {
+        audio.set_paused(deck, false);
+    }
+
+    // Draw fade out button and its length
+    if ui.button(&im_str!("Fade out##Deck {}", name), [80., 30.]) {
+        audio.fade_out(deck, state.fade_time);
+    }
+
+    ui.same_line(spacing);
+
+    let width_tok = ui.push_item_width(80.);
+    Slider::new(&im_str!("##Fade time {}", name))
+        .range(1.0..=30.)
+        .display_format(im_str!("%.0f s"))
+        .build(ui, &mut state.fade_time);
+    width_tok.pop(ui);
+
+    // Draw loaded song
+    ui.text("Loaded song:");
+    ui.text_wrapped(audio.deck_song(deck).unwrap_or(im_str!("Load song below")));
+
+    // Draw paused/playing
+    ui.text(if audio.get_paused(deck) {
+        "Status: Paused"
+    } else if audio.get_fading(deck) {
+        "Status: Fading out"
+    } else {
+        "Status: Playing"
+    });
+
+    // Draw the waveform overview, which seeks to where it is clicked
+    let (samples, max_samples) = audio.music_samples(deck);
+    let pos = ui.cursor_screen_pos();
+    if ui.invisible_button(&im_str!("##Overview {}", name), OVERVIEW_SIZE) {
+        let x = (ui.io().mouse_pos[0] - pos[0]) / OVERVIEW_SIZE[0];
+        audio.jump_samples(deck, (x.clamp(0., 1.) * max_samples as f32) as usize);
+    }
+
+    // The overlap is marked where the next song starts, before the cue out
+    let markers = audio.deck_markers(deck);
+    let fraction = |frame: usize| frame as f32 / max_samples.max(1) as f32;
+    let mut marks: Vec<_> = Marker::ALL
+        .iter()
+        .filter_map(|&marker| Some((fraction(markers.get(marker)?), marker_color(marker))))
+        .collect();
+    let (loop_start, loop_end) = audio.loop_points(deck);
+    for point in [loop_start, loop_end].iter().flatten() {
+        marks.push((fraction(*point), LOOP_COLOR));
+    }
+    if let Some(seconds) = overlap {
+        let frames = (seconds * audio.sample_rate() as f32) as usize;
+        let start = markers.end(max_samples).saturating_sub(frames);
+        marks.push((fraction(start), [1., 0.6, 0.1, 1.]));
+    }
+    draw_overview(
+        ui,
+        pos,
+        audio.deck_overview(deck),
+        fraction(samples),
+        &marks,
+    );
+
+    // Draw the marker buttons, which set a marker at the playhead, or clear it when right
+    // clicked. Each shows where its marker is, and is greyed out where the playhead would put it
+    // out of order with the others.
+    let sample_rate = audio.sample_rate();
+    for (i, &marker) in Marker::ALL.iter().enumerate() {
+        if i % 2 == 1 {
+            ui.same_line(0.);
+        }
+        let time = match markers.get(marker) {
+            Some(frame) => {
+                let (m, s) = samples_to_minsec(frame, sample_rate);
+                format!("{:02}:{:02}", m, s)
+            }
+            None => "--:--".into(),
+        };
+        let allowed = markers.allows(marker, samples);
+        let color = match allowed {
+            true => marker_color(marker),
+            false => ui.style_color(StyleColor::TextDisabled),
+        };
+        let color = ui.push_style_color(StyleColor::Text, color);
+        let label = im_str!("{} {}##Marker {} {}", marker.name(), time, i, name);
+        if ui.button(&label, [DECK_WIDTH / 2. - 14., 0.]) && allowed {
+            audio.set_marker(deck, marker, Some(samples));
+        }
+        color.pop(ui);
+        if ui.is_item_clicked(MouseButton::Right) {
+            audio.set_marker(deck, marker, None);
+        }
+        if ui.is_item_hovered() {
+            ui.tooltip_text(match allowed {
+                true => "Click to set at the playhead, right click to clear",
+                false => {
+                    "The playhead is out of order with the other markers, right click to clear"
+                }
+            });
+        }
+    }
+
+    // Draw the loop buttons. Loop in and loop out set the loop points at the playhead, and loop
+    // out starts looping.
+    let half = [DECK_WIDTH / 2. - 14., 0.];
+    let looping = audio.get_looping(deck);
+    let color = ui.push_style_color(StyleColor::Text, LOOP_COLOR);
+    for (i, point) in [loop_start, loop_end].iter().enumerate() {
+        if i == 1 {
+            ui.same_line(0.);
+        }
+        let time = match point {
+            Some(frame) => {
+                let (m, s) = samples_to_minsec(*frame, sample_rate);
+                format!("{:02}:{:02}", m, s)
+            }
+            None => "--:--".into(),
+        };
+        let label = ["Loop in", "Loop out"][i];
+        if ui.button(&im_str!("{} {}##Loop {} {}", label, time, i, name), half) {
+            match i {
+                0 => audio.set_loop_start(deck, samples),
+                _ => audio.set_loop_end(deck, samples),
+            }
+        }
+    }
+    color.pop(ui);
+
+    let label = if looping { "Loop off" } else { "Loop on" };
+    if ui.button(&im_str!("{}##Looping {}", label, name), half) {
+        audio.set_looping(deck, !looping);
+    }
+    ui.same_line(0.);
+    let label = if audio.get_exiting_loop(deck) {
+        "Exiting at end"
+    } else {
+        "Exit at end"
+    };
+    if ui.button(&im_str!("{}##Exit loop {}", label, name), half) && looping {
+        audio.exit_loop(deck);
+    }
+
+    // Draw timestamp, and the time left to talk over the intro
+    let ((ts_m, ts_s), (mt_m, mt_s)) = audio.music_timestamp(deck);
+    ui.text(format!(
+        "Timestamp: {:02}:{:02} / {:02}:{:02}",
+        ts_m, ts_s, mt_m, mt_s
+    ));
+    match audio.intro_countdown(deck) {
+        Some(seconds) => ui.text_colored(
+            marker_color(Marker::IntroEnd),
+            format!("Intro: {} s", seconds),
+        ),
+        None => ui.text("Intro: -"),
+    }
+
+    // Draw the timestamp slider, which seeks when dragged or clicked. While it is held it
+    // stays where the mouse put it rather than following the song.
+    let mut samples = state.scrub.unwrap_or(samples as u64);
+    let changed = Slider::new(&im_str!("##Timestamp slider {}", name))
+        .range(0..=max_samples as u64)
+        .display_format(im_str!(""))
+        .build(ui, &mut samples);
+    if changed {
+        audio.jump_samples(deck, samples as usize);
+    }
+    state.scrub = if ui.is_item_active() {
+        Some(samples)
+    } else {
+        None
+    };
+
+    // Draw skip buttons
+    for (i, &seconds) in [-30., -5., 5., 30.].iter().enumerate() {
+        if i > 0 {
+            ui.same_line(0.);
+        }
+        if ui.button(&im_str!("{:+}##Skip {} {}", seconds, i, name), [50., 0.]) {
+            audio.skip(deck, seconds);
+        }
+    }
+
+    // Draw jump-to-time. An empty or invalid time does nothing.
+    let jump = ui.button(&im_str!("Jump to##Deck {}", name), [80., 30.]);
+
+    ui.same_line(spacing);
+
+    let width_tok = ui.push_item_width(80.);
+    let entered = ui
+        .input_text(&im_str!("##Jump time {}", name), &mut state.jump_time)
+        .enter_returns_true(true)
+        .build();
+    width_tok.pop(ui);
+
+    if jump || entered {
+        if let Some(seconds) = parse_time(state.jump_time.to_str().trim()) {
+            audio.jump_song(deck, seconds);
+            state.jump_time.clear();
+        }
+    }
+
+    // Draw tempo and pitch, which change independently of each other
+    let width_tok = ui.push_item_width(150.);
+    let changed = Slider::new(&im_str!("Tempo##{}", name))
+        .range(-20.0..=20.)
+        .display_format(im_str!("%+.1f %%"))
+        .build(ui, &mut state.tempo);
+    if changed {
+        audio.set_tempo(deck, state.tempo);
+    }
+
+    let changed = Slider::new(&im_str!("Key##{}", name))
+        .range(-12.0..=12.)
+        .display_format(im_str!("%+.1f st"))
+        .build(ui, &mut state.pitch);
+    if changed {
+        audio.set_pitch(deck, state.pitch);
+    }
+    width_tok.pop(ui);
+
+    if ui.button(&im_str!("Reset tempo and key##{}", name), [0., 0.]) {
+        state.tempo = 0.;
+        state.pitch = 0.;
+        audio.set_tempo(deck, 0.);
+        audio.set_pitch(deck, 0.);
+    }
+}
+
+/// Returns the color a marker is shown in
+fn marker_color(marker: Marker) -> [f32; 4] {
+    match marker {
+        Marker::CueIn | Marker::CueOut => [1., 0.3, 0.3, 1.],
+        Marker::IntroEnd | Marker::OutroStart => [0.4, 1., 0.4, 1.],
+    }
+}
+
+/// Draws a song's waveform overview at pos, lighter up to the playhead at played (0 to 1), with
+/// a line at each mark's position (also 0 to 1) in its color. Only the background is drawn while
+/// there is no overview.
+fn draw_overview(
+    ui: &Ui,
+    pos: [f32; 2],
+    overview: Option<&Overview>,
+    played: f32,
+    marks: &[(f32, [f32; 4])],
+) {
+    let [width, height] = OVERVIEW_SIZE;
+    let middle = pos[1] + height / 2.;
+    let x = |fraction: f32| pos[0] + fraction.clamp(0., 1.) * width;
+
+    let draw_list = ui.get_window_draw_list();
+    draw_list
+        .add_rect(pos, [pos[0] + width, pos[1] + height], [0.1, 0.1, 0.1, 1.])
+        .filled(true)
+        .build();
+
+    // One line per pixel column, from the lowest to the highest sample it covers
+    if let Some(overview) = overview {
+        let points = overview.min.len();
+        let columns = width as usize;
+        for column in 0..columns {
+            let start = column * points / columns;
+            let end = ((column + 1) * points / columns).max(start + 1);
+            let min = overview.min[start..end].iter().copied().fold(0., f32::min);
+            let max = overview.max[start..end].iter().copied().fold(0., f32::max);
+
+            let color = if (column as f32) < played * width {
+                [0.5, 0.8, 1., 1.]
+            } else {
+                [0.2, 0.4, 0.6, 1.]
+            };
+            let x = pos[0] + column as f32 + 0.5;
+            draw_list
+                .add_line(
+                    [x, middle - max.min(1.) * height / 2.],
+                    [x, middle - min.max(-1.) * height / 2. + 1.],
+                    color,
+                )
+                .build();
+        }
+    }
+
+    for &(mark, color) in marks {
+        draw_list
+            .add_line([x(mark), pos[1]], [x(mark), pos[1] + height], color)
+            .build();
+    }
+    draw_list
+        .add_line(
+            [x(played), pos[1]],
+            [x(played), pos[1] + height],
+            [1., 1., 1., 1.],
+        )
+        .build();
+}
+
+/// Draws the cart wall: a button per cart showing its key, or the time it has left while it
+/// plays, with its gain below. Also triggers the carts whose key was pressed.
+fn draw_carts(ui: &Ui, state: &mut UIState, audio: &Audio) {
+    let per_row = carts_per_row(state.window_size[0], ui.clone_style().item_spacing[0]);
+
+    // Keys only count when pressed, not when repeating, and not while typing in a text field
+    let typing = ui.io().want_text_input;
+
+    for (i, cart) in state.carts.iter_mut().enumerate() {
+        if let Some((key, _)) = cart.key {
+            if !typing && ui.key_pressed_amount(key, f32::INFINITY, 0.) > 0 {
+                audio.trigger_cart(i);
+            }
+        }
+
+        if i % per_row != 0 {
+            ui.same_line(0.);
+        }
+
+        let remaining = audio.cart_remaining(i);
+        ui.group(|| {
+            let label = match (&cart.key, remaining > 0.) {
+                (_, true) => im_str!("{}\n{:.1} s##Cart {}", cart.name, remaining, i),
+                (Some((_, key)), false) => im_str!("{}\n[{}]##Cart {}", cart.name, key, i),
+                (None, false) => im_str!("{}##Cart {}", cart.name, i),
+            };
+
+            // Playing carts light up
+            let color = if remaining > 0. {
+                Some(ui.push_style_color(StyleColor::Button, [0.8, 0.4, 0.1, 1.]))
+            } else {
+                None
+            };
+            if ui.button(&label, CART_SIZE) {
+                audio.trigger_cart(i);
+            }
+            if let Some(color) = color {
+                color.pop(ui);
+            }
+
+            let width_tok = ui.push_item_width(CART_SIZE[0]);
+            let changed = Slider::new(&im_str!("##Cart gain {}", i))
+                .range(-30.0..=6.0)
+                .display_format(im_str!("%+.1f dB"))
+                .build(ui, &mut cart.gain);
+            if changed {
+                audio.set_cart_gain(i, cart.gain);
+            }
+            width_tok.pop(ui);
+        });
+    }
+}
+
+/// Draws the column of a channel strip: its name, fader, and switches. max_db is the top of the
+/// fader.
+fn draw_strip(
+    ui: &Ui,
+    audio: &Audio,
+    strip: Strip,
+    name: &ImStr,
+    max_db: f32,
+    state: &mut StripState,
+) {
+    // Unique id suffix for the widgets of this strip
+    let id = match strip {
+        Strip::Input(i) => format!("Input {}", i),
+        Strip::Music => "Music".to_string(),
+    };
+
+    ui.set_current_column_width(STRIP_WIDTH);
+    ui.text(name);
+    let top_left = ui.cursor_screen_pos();
+    if draw_fader(ui, &im_str!("##{} volume", id), max_db, &mut state.volume) {
+        audio.set_volume(strip, state.volume);
+    }
+
+    // Input meter beside the fader
+    let pos = [top_left[0] + FADER_SIZE[0] + 26., top_left[1]];
+    let level = audio.strip_meter(strip);
+    draw_meter(ui, &im_str!("##{} meter", id), pos, &mut state.meter, level);
+
+    if ui.checkbox(&im_str!("Mute##{}", id), &mut state.mute) {
+        audio.set_mute(strip, state.mute);
+    }
+
+    if ui.checkbox(&im_str!("Solo##{}", id), &mut state.solo) {
+        audio.set_solo(strip, state.solo);
+    }
+
+    if ui.checkbox(&im_str!("Invert##{}", id), &mut state.invert) {
+        audio.set_invert(strip, state.invert);
+    }
+
+    if let Strip::Input(i) = strip {
+        if ui.checkbox(&im_str!("Duck music##{}", id), &mut state.duck) {
+            audio.set_duck(i, state.duck);
+        }
+    }
+}
+
+/// Draws a vertical fader in dB from MIN_DB (off) to max_db, with a mark at unity gain beside
+/// it. Returns true if the value changed.
+fn draw_fader(ui: &Ui, label: &ImStr, max_db: f32, db: &mut f32) -> bool {
+    let top_left = ui.cursor_screen_pos();
+
+    let format = if *db <= MIN_DB {
+        im_str!("-inf dB")
+    } else {
+        im_str!("%+.1f dB")
+    };
+
+    let changed = VerticalSlider::new(label, FADER_SIZE)
+        .range(MIN_DB..=max_db)
+        .display_format(format)
+        .build(ui, db);
+
+    // Find where the center of the slider grab is at 0 dB, the same way imgui positions it
+    let style = ui.clone_style();
+    let grab_padding = 2.;
+    let travel = FADER_SIZE[1] - 2. * grab_padding - style.grab_min_size;
+    let unity = (0. - MIN_DB) / (max_db - MIN_DB);
+    let y = top_left[1] + grab_padding + style.grab_min_size / 2. + (1. - unity) * travel;
+
+    // Mark unity with a tick and label to the right of the slider
+    let x = top_left[0] + FADER_SIZE[0];
+    let color = [1., 1., 1., 0.8];
+    let draw_list = ui.get_window_draw_list();
+    draw_list.add_line([x + 2., y], [x + 12., y], color).build();
+    let text_height = ui.text_line_height();
+    draw_list.add_text([x + 14., y - text_height / 2.], color, "0");
+
+    changed
+}
+
+/// Draws a level meter as tall as a fader with its top left corner at pos, given the peak and RMS
+/// level as linear amplitudes. The bar shows the RMS level, with the peak level lighter above it
+/// and the recent highest peak marked by a line. A box at the top lights up red on clipping, until
+/// the meter is clicked.
+fn draw_meter(ui: &Ui, id: &ImStr, pos: [f32; 2], state: &mut MeterState, level: (f32, f32)) {
+    let (peak, rms) = (gain_to_db(level.0), gain_to_db(level.1));
+
+    // Update the peak display with this frame's levels
+    let dt = ui.io().delta_time;
+    state.peak = peak.max(state.peak - PEAK_FALL * dt);
+    state.hold_time -= dt;
+    if peak >= state.hold || state.hold_time <= 0. {
+        state.hold = peak;
+        state.hold_time = PEAK_HOLD;
+    }
+    state.clip |= level.0 >= 1.;
+
+    // Clicking the meter resets the clip box and peak hold
+    let cursor = ui.cursor_screen_pos();
+    ui.set_cursor_screen_pos(pos);
+    if ui.invisible_button(id, [METER_WIDTH, FADER_SIZE[1]]) {
+        state.clip = false;
+        state.hold = MIN_DB;
+    }
+    ui.set_cursor_screen_pos(cursor);
+
+    // The clip box sits above the bar, which goes from MIN_DB at the bottom to 0 dBFS at the top
+    let clip_height = 8.;
+    let top = pos[1] + clip_height + 2.;
+    let bottom = pos[1] + FADER_SIZE[1];
+    let right = pos[0] + METER_WIDTH;
+    let y = |db: f32| bottom - (db - MIN_DB) / -MIN_DB * (bottom - top);
+
+    let draw_list = ui.get_window_draw_list();
+    let clip_color = if state.clip {
+        [1., 0.1, 0.1, 1.]
+    } else {
+        [0.2, 0.2, 0.2, 1.]
+    };
+    draw_list
+        .add_rect(pos, [right, pos[1] + clip_height], clip_color)
+        .filled(true)
+        .build();
+    draw_list
+        .add_rect([pos[0], top], [right, bottom], [0.2, 0.2, 0.2, 1.])
+        .filled(true)
+        .build();
+    draw_list
+        .add_rect(
+            [pos[0], y(state.peak)],
+            [right, bottom],
+            [0.3, 0.6, 0.3, 1.],
+        )
+        .filled(true)
+        .build();
+    draw_list
+        .add_rect([pos[0], y(rms)], [right, bottom], [0.3, 0.9, 0.3, 1.])
+        .filled(true)
+        .build();
+    if state.hold > MIN_DB {
+        let y = y(state.hold);
+        draw_list
+            .add_line([pos[0], y], [right, y], [1., 1., 0.4, 1.])
+            .build();
+    }
+}
+
+/// Formats a loudness in LUFS for display
+fn format_lufs(lufs: f32) -> String {
+    if lufs == f32::NEG_INFINITY {
+        "-inf LUFS".to_string()
+    } else {
+        format!("{:.1} LUFS", lufs)
+    }
+}